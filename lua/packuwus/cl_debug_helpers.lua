@@ -11,3 +11,45 @@ concommand.Add("packuwus_dump_file", function(_, _, _, path)
 
     log("packuwus_dump_file: Dumping \"%s\" to console\n%s", fixedPath, files[fixedPath])
 end)
+
+-- compares per file and solid layouts on currently unpacked files
+concommand.Add("packuwus_benchmark_unpack", function()
+    local paths = table.GetKeys(files)
+    table.sort(paths)
+
+    local perFile = {}
+    local perFileBytes = 0
+    local stream = {}
+
+    for i, path in ipairs(paths) do
+        perFile[i] = util.Compress(files[path]) or ""
+        perFileBytes = perFileBytes + #perFile[i]
+        stream[i] = files[path]
+    end
+
+    local solid = util.Compress(table.concat(stream)) or ""
+
+    local startTime = SysTime()
+
+    for i = 1, #perFile do
+        util.Decompress(perFile[i])
+    end
+
+    local perFileTime = SysTime() - startTime
+
+    startTime = SysTime()
+
+    local decompressed = util.Decompress(solid) or ""
+    local offset = 0
+
+    for i = 1, #stream do
+        string.sub(decompressed, offset + 1, offset + #stream[i])
+        offset = offset + #stream[i]
+    end
+
+    local solidTime = SysTime() - startTime
+
+    log("packuwus_benchmark_unpack: %d files", #paths)
+    log("Per file layout: %d bytes, unpacked in %.4f seconds", perFileBytes, perFileTime)
+    log("Solid layout: %d bytes, unpacked in %.4f seconds", #solid, solidTime)
+end)
@@ -1,4 +1,5 @@
 __PackUwUs__old_CompileFile = CompileFile
+__PackUwUs__old_include = include
 
 local log = PackUwUs.Log
 
@@ -26,3 +27,35 @@ function CompileFile(path)
 
     return PackUwUs.LoadFile(path)
 end
+
+-- virtual files aren't on disk, so vanilla include can't find them
+local function resolveVirtualFile(path)
+    if PackUwUs.IsVirtualFile(path) then
+        return path
+    end
+
+    local info = debug.getinfo(3, "S")
+    local callerDir = info and string.match(info.short_src, "^(.*)/[^/]*$")
+
+    if callerDir then
+        local relativePath = callerDir .. "/" .. path
+
+        if PackUwUs.IsVirtualFile(relativePath) then
+            return relativePath
+        end
+    end
+
+    return nil
+end
+
+function include(path)
+    local virtualPath = resolveVirtualFile(path)
+
+    if not virtualPath then
+        return __PackUwUs__old_include(path)
+    end
+
+    log("Including virtual file \"%s\"", virtualPath)
+
+    return PackUwUs.LoadFile(virtualPath)()
+end
@@ -3,7 +3,13 @@
 ]]
 PackUwUs.Files = PackUwUs.Files or {}
 
+--[[
+    { string path = true }, files that exist only in pack
+]]
+PackUwUs.VirtualFiles = PackUwUs.VirtualFiles or {}
+
 local files = PackUwUs.Files
+local virtualFiles = PackUwUs.VirtualFiles
 
 local log = PackUwUs.Log
 local dbg = PackUwUs.Debug
@@ -16,11 +22,17 @@ function PackUwUs.HasFile(path)
     return files[fixedPath] ~= nil
 end
 
-function PackUwUs.GetPackedFilePath()
-    local filename = "download/data/serve_packuwus/" .. PackUwUs.packuwus_hash:GetString() .. ".bsp"
+function PackUwUs.IsVirtualFile(path)
+    local fixedPath = PackUwUs.FixPath(path)
+
+    return virtualFiles[fixedPath] == true and files[fixedPath] ~= nil
+end
+
+function PackUwUs.GetServedFilePath(name)
+    local filename = "download/data/serve_packuwus/" .. name .. ".bsp"
 
     if not file.Exists(filename, "GAME") then
-        err("Cannot get packed file path: packed file doesn't exist!")
+        err("Cannot get served file path: \"%s\" doesn't exist!", filename)
 
         return nil
     end
@@ -28,49 +40,186 @@ function PackUwUs.GetPackedFilePath()
     return filename
 end
 
-function PackUwUs.Unpack()
-    local function readString(f)
-        local s = ""
+local function readString(f)
+    local s = ""
+
+    while true do
+        if f:EndOfFile() then
+            return nil
+        end
+
+        local c = f:Read(1)
+
+        if c == "\0" then
+            break
+        end
+
+        s = s .. c
+    end
+
+    return s
+end
+
+--[[
+    Returns manifest:
+    {
+        string base = name of base manifest or nil,
+        chunks = { { string name, { string path, ... } }, ... },
+        removed = { string path, ... },
+        dictionary = { string entry, ... } or nil,
+        virtualFiles = { string path, ... },
+    }
+]]
+function PackUwUs.ReadManifest(name)
+    local manifestPath = PackUwUs.GetServedFilePath(name)
+
+    if not manifestPath then
+        err("Failed to read manifest %s: no manifest file!", name)
+
+        return nil
+    end
+
+    local f = file.Open(manifestPath, "rb", "GAME")
+
+    if not f then
+        err("Failed to read manifest: failed to open \"%s\"", manifestPath)
+
+        return nil
+    end
+
+    local base = readString(f)
+
+    if not base then
+        err("Failed to read manifest %s: unexpected EOF while reading base name!", name)
+
+        f:Close()
+
+        return nil
+    end
+
+    local manifest = {
+        base = base ~= "" and base or nil,
+        chunks = {},
+        removed = {},
+        virtualFiles = {},
+    }
+
+    for _ = 1, f:ReadULong() do
+        local chunkName = readString(f)
+
+        if not chunkName then
+            err("Failed to read manifest %s: unexpected EOF while reading chunk name!", name)
+
+            f:Close()
+
+            return nil
+        end
+
+        local paths = {}
+
+        for i = 1, f:ReadULong() do
+            paths[i] = readString(f)
+
+            if not paths[i] then
+                err("Failed to read manifest %s: unexpected EOF while reading paths of chunk %s!", name, chunkName)
+
+                f:Close()
 
-        while true do
-            if f:EndOfFile() then
                 return nil
             end
+        end
 
-            local c = f:Read(1)
+        table.insert(manifest.chunks, { chunkName, paths })
+    end
 
-            if c == "\0" then
-                break
-            end
+    for i = 1, f:ReadULong() do
+        manifest.removed[i] = readString(f)
+
+        if not manifest.removed[i] then
+            err("Failed to read manifest %s: unexpected EOF while reading removed paths!", name)
 
-            s = s .. c
+            f:Close()
+
+            return nil
         end
+    end
+
+    local dictionarySize = f:ReadULong()
+
+    if dictionarySize > 0 then
+        manifest.dictionary = {}
+
+        for i = 1, dictionarySize do
+            manifest.dictionary[i] = readString(f)
+
+            if not manifest.dictionary[i] then
+                err("Failed to read manifest %s: unexpected EOF while reading dictionary!", name)
 
-        return s
+                f:Close()
+
+                return nil
+            end
+        end
     end
 
-    log("Unpacking files")
+    for i = 1, f:ReadULong() do
+        manifest.virtualFiles[i] = readString(f)
 
-    for k, _ in pairs(files) do
-        files[k] = nil
+        if not manifest.virtualFiles[i] then
+            err("Failed to read manifest %s: unexpected EOF while reading virtual files!", name)
+
+            f:Close()
+
+            return nil
+        end
     end
 
-    local packedFilePath = PackUwUs.GetPackedFilePath()
+    f:Close()
+
+    return manifest
+end
 
-    if not packedFilePath then
-        err("Failed to unpack: no packed file!")
+local LAYOUT_PER_FILE = 0
+local LAYOUT_SOLID = 1
 
-        return false
+local CODEC_NONE = 0
+local CODEC_LZMA = 1
+
+local function decompress(codec, data)
+    if codec == CODEC_NONE then
+        return data
+    elseif codec == CODEC_LZMA then
+        return util.Decompress(data)
     end
 
-    local f = file.Open(packedFilePath, "rb", "GAME")
+    return nil
+end
 
-    if not f then
-        err("Failed to unpack: failed to open \"%s\"", packedFilePath)
+-- see src/dictionary.rs
+local function decodeDictionary(content, dictionary)
+    return (string.gsub(content, "\1(.)", function(c)
+        local index = string.byte(c)
 
-        return false
+        if index == 0 then
+            return "\1"
+        end
+
+        return dictionary[index]
+    end))
+end
+
+local function addFile(path, content, dictionary)
+    if dictionary then
+        content = decodeDictionary(content, dictionary)
     end
 
+    path = PackUwUs.FixPath(path)
+    files[path] = content
+
+    dbg("Readed %s (len: %d)", path, #content)
+end
+
+local function unpackPerFile(f, dictionary)
     local filesCount = 0
 
     while true do
@@ -83,50 +232,232 @@ function PackUwUs.Unpack()
         if not path then
             err("Failed to unpack: unexpected EOF while reading path!")
 
-            return false
+            return nil
         end
 
         if f:EndOfFile() then
-            err("Failed to unpack: unexpected EOF while reading size!")
+            err("Failed to unpack: unexpected EOF while reading codec of %s!", path)
 
-            return false
+            return nil
         end
 
+        local codec = f:ReadByte()
         local size = f:ReadULong()
 
-        if f:EndOfFile() then
+        if f:EndOfFile() and size ~= 0 then
             err("Failed to unpack: unexpected EOF while reading size of %s!", path)
 
-            return false
+            return nil
         end
 
-        local content = f:Read(size)
+        local content = size == 0 and "" or f:Read(size)
 
         if #content ~= size then
             err("Failed to unpack: readed content size of %s differs (%d != %d)!", path, #content, size)
 
-            return false
+            return nil
         end
 
-        content = util.Decompress(content)
+        content = decompress(codec, content)
 
         if not content then
-            err("Failed to unpack: decompress %s failed!", path)
+            err("Failed to unpack: decompress %s (codec %d) failed!", path, codec)
 
-            return false
+            return nil
         end
 
         filesCount = filesCount + 1
 
-        path = PackUwUs.FixPath(path)
-        files[path] = content
+        addFile(path, content, dictionary)
+    end
+
+    return filesCount
+end
+
+local function unpackSolid(f, codec, dictionary)
+    local index = {}
+
+    for i = 1, f:ReadULong() do
+        local path = readString(f)
+
+        if not path then
+            err("Failed to unpack: unexpected EOF while reading solid index!")
+
+            return nil
+        end
+
+        index[i] = { path, f:ReadULong(), f:ReadULong() }
+    end
+
+    local size = f:ReadULong()
+    local stream = size == 0 and "" or f:Read(size)
+
+    if not stream or #stream ~= size then
+        err("Failed to unpack: readed solid stream size differs (%d != %d)!", stream and #stream or 0, size)
+
+        return nil
+    end
+
+    stream = decompress(codec, stream)
+
+    if not stream then
+        err("Failed to unpack: decompress solid stream (codec %d) failed!", codec)
+
+        return nil
+    end
+
+    for _, entry in ipairs(index) do
+        local path, offset, contentSize = entry[1], entry[2], entry[3]
+
+        local content = string.sub(stream, offset + 1, offset + contentSize)
+
+        if #content ~= contentSize then
+            err("Failed to unpack: readed content size of %s differs (%d != %d)!", path, #content, contentSize)
+
+            return nil
+        end
 
-        dbg("Readed %s (len: %d)", path, #content)
+        addFile(path, content, dictionary)
+    end
+
+    return #index
+end
+
+function PackUwUs.UnpackChunk(name, dictionary)
+    local chunkPath = PackUwUs.GetServedFilePath(name)
+
+    if not chunkPath then
+        err("Failed to unpack chunk %s: no chunk file!", name)
+
+        return nil
+    end
+
+    local f = file.Open(chunkPath, "rb", "GAME")
+
+    if not f then
+        err("Failed to unpack chunk %s: failed to open \"%s\"", name, chunkPath)
+
+        return nil
+    end
+
+    local layout = f:ReadByte()
+    local codec = f:ReadByte()
+    local level = f:ReadByte()
+    local dictionaryEncoded = f:ReadByte() == 1
+
+    dbg("Chunk %s: layout %d, codec %d, level %d, dictionary %s", name, layout, codec, level,
+        tostring(dictionaryEncoded))
+
+    local filesCount
+
+    if dictionaryEncoded and not dictionary then
+        err("Failed to unpack chunk %s: chunk is dictionary encoded, but manifest has no dictionary!", name)
+
+        f:Close()
+
+        return nil
+    end
+
+    if not dictionaryEncoded then
+        dictionary = nil
+    end
+
+    if layout == LAYOUT_PER_FILE then
+        filesCount = unpackPerFile(f, dictionary)
+    elseif layout == LAYOUT_SOLID then
+        filesCount = unpackSolid(f, codec, dictionary)
+    else
+        err("Failed to unpack chunk %s: unknown layout %d!", name, layout)
     end
 
     f:Close()
 
-    ok("Finished unpacking %d files", filesCount)
+    if filesCount then
+        dbg("Unpacked chunk %s (%d files)", name, filesCount)
+    end
+
+    return filesCount
+end
+
+local function applyManifest(name, isTop)
+    local manifest = PackUwUs.ReadManifest(name)
+
+    if not manifest then
+        err("Failed to unpack: failed to read manifest %s!", name)
+
+        return nil
+    end
+
+    -- every manifest lists all virtual files of the pack, so only the top one matters
+    if isTop then
+        for _, path in ipairs(manifest.virtualFiles) do
+            virtualFiles[PackUwUs.FixPath(path)] = true
+        end
+    end
+
+    local filesCount = 0
+
+    if manifest.base then
+        dbg("Manifest %s is delta, applying base %s first", name, manifest.base)
+
+        filesCount = applyManifest(manifest.base)
+
+        if not filesCount then
+            return nil
+        end
+    end
+
+    for _, chunk in ipairs(manifest.chunks) do
+        local chunkName, paths = chunk[1], chunk[2]
+
+        local chunkFilesCount = PackUwUs.UnpackChunk(chunkName, manifest.dictionary)
+
+        if not chunkFilesCount then
+            return nil
+        end
+
+        if chunkFilesCount ~= #paths then
+            err("Failed to unpack: chunk %s has %d files, manifest says %d!", chunkName, chunkFilesCount, #paths)
+
+            return nil
+        end
+
+        filesCount = filesCount + chunkFilesCount
+    end
+
+    for _, path in ipairs(manifest.removed) do
+        dbg("Removing %s", path)
+
+        files[PackUwUs.FixPath(path)] = nil
+    end
+
+    return filesCount
+end
+
+function PackUwUs.Unpack()
+    log("Unpacking files")
+
+    for k, _ in pairs(files) do
+        files[k] = nil
+    end
+
+    for k, _ in pairs(virtualFiles) do
+        virtualFiles[k] = nil
+    end
+
+    local hash = PackUwUs.packuwus_hash:GetString()
+
+    if hash == "" then
+        log("Server doesn't serve packed files, loading files as usual")
+
+        return true
+    end
+
+    if not applyManifest(hash, true) then
+        return false
+    end
+
+    ok("Finished unpacking %d files (%d virtual) from %s", table.Count(files), table.Count(virtualFiles), hash)
 
     return true
 end
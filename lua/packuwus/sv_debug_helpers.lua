@@ -9,3 +9,58 @@ concommand.Add("packuwus_repack_sync", function(ply)
 
     PackUwUs.PackSync()
 end)
+
+concommand.Add("packuwus_cancel", function(ply)
+    if IsValid(ply) then return end
+
+    PackUwUs.CancelPack()
+end)
+
+concommand.Add("packuwus_benchmark", function(ply)
+    if IsValid(ply) then return end
+
+    for _, result in ipairs(PackUwUs_BenchmarkLayouts()) do
+        PackUwUs.Log("%s layout: %d bytes in %d chunks, built in %.3f seconds",
+            result.solid and "Solid" or "Per file", result.bytes, result.chunks, result.seconds)
+    end
+end)
+
+concommand.Add("packuwus_list_files", function(ply)
+    if IsValid(ply) then return end
+
+    local paths = PackUwUs_ListFiles()
+
+    for _, path in ipairs(paths) do
+        PackUwUs.Log("%s (%d bytes)", path, #PackUwUs_GetFile(path))
+    end
+
+    PackUwUs.Log("%d files packed", #paths)
+end)
+
+concommand.Add("packuwus_reconcile", function(ply)
+    if IsValid(ply) then return end
+
+    local report = PackUwUs_ReconcileReport()
+
+    for _, path in ipairs(report.unpacked) do
+        PackUwUs.Log("Not packed, downloaded separately: %s", path)
+    end
+
+    for _, path in ipairs(report.orphaned) do
+        PackUwUs.Log("Packed, but not sent to clients: %s", path)
+    end
+
+    for _, path in ipairs(report.ingested) do
+        PackUwUs.Log("Ingested from disk: %s", path)
+    end
+
+    PackUwUs.Log("%d unpacked, %d orphaned, %d ingested on last pack",
+        #report.unpacked, #report.orphaned, #report.ingested)
+end)
+
+concommand.Add("packuwus_retrain_dictionary", function(ply)
+    if IsValid(ply) then return end
+
+    PackUwUs_RetrainDictionary()
+    PackUwUs.Log("Dictionary will be retrained by the next pack")
+end)
@@ -1,6 +1,22 @@
-PackUwUs.NeedToRepack = PackUwUs.NeedToRepack or false
-PackUwUs.Ready        = PackUwUs.Ready or false
-PackUwUs.Packing      = PackUwUs.Packing or false
+PackUwUs.Ready   = PackUwUs.Ready or false
+PackUwUs.Packing = PackUwUs.Packing or false
+
+local packuwus_delta = CreateConVar("packuwus_delta", "0", FCVAR_ARCHIVE,
+    "Publish patch packs on top of last full pack instead of repacking everything")
+local packuwus_codec = CreateConVar("packuwus_codec", "lzma", FCVAR_ARCHIVE,
+    "Pack compression codec: lzma, none")
+local packuwus_codec_level = CreateConVar("packuwus_codec_level", "9", FCVAR_ARCHIVE,
+    "Pack compression level (0-9)")
+local packuwus_compress_threshold = CreateConVar("packuwus_compress_threshold", "0", FCVAR_ARCHIVE,
+    "Files smaller than this amount of bytes are stored uncompressed")
+local packuwus_solid = CreateConVar("packuwus_solid", "0", FCVAR_ARCHIVE,
+    "Compress whole pack as single stream in one chunk")
+local packuwus_dictionary = CreateConVar("packuwus_dictionary", "0", FCVAR_ARCHIVE,
+    "Encode packed files with dictionary trained on whole pack")
+local packuwus_ingest_missing = CreateConVar("packuwus_ingest_missing", "0", FCVAR_ARCHIVE,
+    "Read client files which weren't packed from disk and pack them too")
+PackUwUs.packuwus_reconcile_interval = CreateConVar("packuwus_reconcile_interval", "30", FCVAR_ARCHIVE,
+    "Seconds between checks for packed files deleted from disk, 0 disables")
 
 local log = PackUwUs.Log
 local warn = PackUwUs.Warn
@@ -37,28 +53,89 @@ function PackUwUs.ShouldPack(path)
     return true
 end
 
+local function setOption(name, ...)
+    local success, result = pcall(PackUwUs_SetOption, name, ...)
+
+    if not success then
+        err("Failed to set option %s: %s", name, result)
+    end
+end
+
+function PackUwUs.ApplyOptions()
+    if not PackUwUs_SetOption then
+        return -- internal module isn't loaded yet
+    end
+
+    setOption("delta", packuwus_delta:GetBool())
+    setOption("codec", packuwus_codec:GetString(), packuwus_codec_level:GetInt())
+    setOption("compress_threshold", packuwus_compress_threshold:GetInt())
+    setOption("solid", packuwus_solid:GetBool())
+    setOption("dictionary", packuwus_dictionary:GetBool())
+    setOption("ingest_missing", packuwus_ingest_missing:GetBool())
+end
+
+-- packed files are useless if clients can't be told to unpack them, so without
+-- download rewrite nothing is packed and clients load everything the vanilla way
+function PackUwUs.CanServe()
+    if not PackUwUs_Capabilities then
+        return false
+    end
+
+    local capabilities = PackUwUs_Capabilities()
+
+    return capabilities.packing and capabilities.download_rewrite
+end
+
+local warnedCantServe = false
+
+local function checkCanServe()
+    if PackUwUs.CanServe() then
+        return true
+    end
+
+    if not warnedCantServe then
+        warnedCantServe = true
+
+        warn("Packing is unavailable because some hooks are missing, clients will load files as usual")
+    end
+
+    PackUwUs.packuwus_hash:SetString("")
+
+    return false
+end
+
 function PackUwUs.PackSync(onlyCheck)
     if PackUwUs.Packing then
         if onlyCheck ~= true then
-            PackUwUs.NeedToRepack = true
+            dbg("Async pack is running, queueing follow-up pack")
 
-            dbg("NeedToRepack set to true")
+            PackUwUs.PackAsync()
         end
 
         return
     end
 
+    if not checkCanServe() then
+        return
+    end
+
+    PackUwUs.ApplyOptions()
+
     local startTime = SysTime()
 
     log("Packing UwUs...")
     dbg("Packing synchronously")
 
-    local success, result = pcall(PackUwUs_PackSync)
+    local success, result, packErr = pcall(PackUwUs_PackSync)
 
     if not success then
         err("Error occured while packing: %s", result)
     elseif result == false then
-        ok("Nothing to pack!")
+        if packErr.code == "nothing_to_pack" then
+            ok("Nothing to pack!")
+        else
+            err("Error occured while packing (%s): %s", packErr.code, packErr.message)
+        end
     else
         ok("Pack complete in %.2f seconds! Hash is %s", SysTime() - startTime, result)
 
@@ -66,42 +143,103 @@ function PackUwUs.PackSync(onlyCheck)
     end
 end
 
-function PackUwUs.PackAsync(onlyCheck)
-    if PackUwUs.Packing then
-        if onlyCheck ~= true then
-            PackUwUs.NeedToRepack = true
+local function onPackProgress(progress)
+    dbg("Packed %d/%d files, %d bytes written", progress.files, progress.total, progress.bytes)
+end
 
-            dbg("NeedToRepack set to true")
-        end
+local lastPackId = 0
 
+function PackUwUs.PackAsync(onlyCheck)
+    if PackUwUs.Packing and onlyCheck == true then
         return
     end
 
-    local startTime = SysTime()
+    if not checkCanServe() then
+        return
+    end
+
+    PackUwUs.ApplyOptions()
+
+    local packId = lastPackId + 1
+
+    local packStarted = PackUwUs_PackAsync(function(packErr, result)
+        -- every callback of a follow-up pack gets the same result, only the latest one reports it
+        if packId ~= lastPackId then
+            return
+        end
 
-    local packStarted = PackUwUs_PackAsync(function(packErr, hash)
         PackUwUs.Packing = false
 
         if packErr then
-            err("Error occured while packing: %s", packErr)
+            if packErr.code == "cancelled" then
+                warn("Pack was cancelled")
+            else
+                err("Error occured while packing (%s): %s", packErr.code, packErr.message)
+            end
         else
-            ok("Pack complete in %.2f seconds! Hash is %s", SysTime() - startTime, hash)
+            ok("%s pack complete in %.2f seconds! %d files, %d chunks, %d bytes. Hash is %s",
+                result.delta and "Delta" or "Full", result.seconds, result.files, result.chunks,
+                result.bytes, result.hash)
 
-            PackUwUs.packuwus_hash:SetString(hash)
-        end
-
-        if PackUwUs.NeedToRepack then
-            warn("NeedToRepack is set while packing, repacking...")
+            if result.unpacked > 0 or result.orphaned > 0 or result.ingested > 0 then
+                dbg("%d client files aren't packed, %d packed files aren't sent to clients, %d ingested " ..
+                    "(see packuwus_reconcile)", result.unpacked, result.orphaned, result.ingested)
+            end
 
-            PackUwUs.PackAsync()
+            PackUwUs.packuwus_hash:SetString(result.hash)
         end
-    end)
+    end, onPackProgress)
 
     if packStarted then
-        log("Packing UwUs...")
-        dbg("Packing asynchronously")
+        if PackUwUs.Packing then
+            warn("Files changed while packing, repacking...")
+        else
+            log("Packing UwUs...")
+            dbg("Packing asynchronously")
+        end
 
-        PackUwUs.NeedToRepack = false
+        lastPackId = packId
         PackUwUs.Packing = true
     end
 end
+
+function PackUwUs.CancelPack()
+    if not PackUwUs_CancelPack() then
+        log("Nothing to cancel")
+    end
+end
+
+-- adds file that exists only in pack, clients can include it like any other file
+function PackUwUs.AddVirtualFile(path, code)
+    path = PackUwUs.FixPath(path)
+
+    dbg("Adding virtual file %s (len: %d)", path, #code)
+
+    PackUwUs_AddVirtualFile(path, PackUwUs.TrimCode(code))
+end
+
+function PackUwUs.RemoveFile(path)
+    local removed, removeErr = PackUwUs_RemoveFile(path)
+
+    if not removed then
+        err("Failed to remove %s from pack (%s): %s", path, removeErr.code, removeErr.message)
+    end
+
+    return removed
+end
+
+-- drops packed files that were deleted from disk or aren't sent to clients anymore
+function PackUwUs.RemoveMissingFiles()
+    -- module could have been unloaded
+    if not PackUwUs_RemoveMissingFiles then
+        return {}
+    end
+
+    local removed = PackUwUs_RemoveMissingFiles()
+
+    for _, path in ipairs(removed) do
+        log("%s is gone, removed it from pack", path)
+    end
+
+    return removed
+end
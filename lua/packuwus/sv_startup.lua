@@ -1,3 +1,24 @@
+-- module removes these timers when it's unloaded
+local function createTimers()
+    timer.Create("PackUwUs auto repack", 1, 0, function()
+        PackUwUs.PackAsync(true)
+    end)
+
+    local lastReconcile = SysTime()
+
+    timer.Create("PackUwUs reconcile", 1, 0, function()
+        local interval = PackUwUs.packuwus_reconcile_interval:GetFloat()
+
+        if interval <= 0 or SysTime() - lastReconcile < interval then
+            return
+        end
+
+        lastReconcile = SysTime()
+
+        PackUwUs.RemoveMissingFiles()
+    end)
+end
+
 if not PackUwUs.Ready then
     require("hook")
 
@@ -9,14 +30,17 @@ if not PackUwUs.Ready then
         PackUwUs_SetPackContent("return unpackMeUwU()()")
         PackUwUs.PackSync()
 
-        timer.Create("PackUwUs auto repack", 1, 0, function()
-            PackUwUs.PackAsync(true)
-        end)
+        createTimers()
     end)
 
     PackUwUs.Log("Loading internal module...")
     require("packuwus")
     PackUwUs.Ok("Internal module loaded!")
 else
+    -- module was re-required after unload
+    if PackUwUs_PackAsync and not timer.Exists("PackUwUs auto repack") then
+        createTimers()
+    end
+
     PackUwUs.PackAsync()
 end
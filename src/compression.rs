@@ -0,0 +1,109 @@
+use gmod_lzma::SZ;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CompressError {
+    #[error("LZMA compression failed with status code {0}")]
+    Lzma(SZ),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ParseCodecError {
+    #[error("Unknown codec \"{0}\". Valid codecs are: none, lzma")]
+    UnknownCodec(String),
+    #[error("Codec \"{0}\" isn't supported, clients can only decompress LZMA")]
+    UnsupportedCodec(String),
+    #[error("Invalid LZMA level {0}. Valid levels are: 0-9")]
+    InvalidLevel(i32),
+}
+
+/// Codec id is written into the pack, client loader picks decompression by it.
+///
+/// There's no zstd: clients decompress with `util.Decompress`, which only knows LZMA, and
+/// a zstd decoder written in Lua would be slower than the download it saves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    None,
+    Lzma(i32),
+}
+
+impl Codec {
+    pub fn parse(name: &str, level: i32) -> Result<Codec, ParseCodecError> {
+        match name {
+            "none" => Ok(Codec::None),
+            "lzma" => {
+                if (0..=9).contains(&level) {
+                    Ok(Codec::Lzma(level))
+                } else {
+                    Err(ParseCodecError::InvalidLevel(level))
+                }
+            }
+            "zstd" => Err(ParseCodecError::UnsupportedCodec(name.to_string())),
+            _ => Err(ParseCodecError::UnknownCodec(name.to_string())),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lzma(_) => 1,
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lzma(level) => *level as u8,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressError> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Lzma(level) => {
+                gmod_lzma::compress(data, *level).or_else(|err| Err(CompressError::Lzma(err)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionOptions {
+    pub codec: Codec,
+    /// Files smaller than this are stored as is
+    pub min_size: usize,
+    /// Compress whole pack as single stream in one chunk, so small files compress against
+    /// each other. Any change re-downloads the whole pack then, delta packs still work.
+    pub solid: bool,
+    /// Encode files with dictionary trained on whole pack before compressing them.
+    /// Dictionary is kept until [`crate::packuwus::PackUwUs::retrain_dictionary`].
+    pub dictionary: bool,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            codec: Codec::Lzma(9),
+            min_size: 0,
+            solid: false,
+            dictionary: false,
+        }
+    }
+}
+
+impl CompressionOptions {
+    /// Engine packets are always LZMA, clients can't decode anything else there
+    pub fn packet_level(&self) -> i32 {
+        match self.codec {
+            Codec::Lzma(level) => level,
+            Codec::None => 9,
+        }
+    }
+
+    pub fn codec_for(&self, data: &[u8]) -> Codec {
+        if data.len() < self.min_size {
+            Codec::None
+        } else {
+            self.codec
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::sdk::filesystem::{FileSystem, WriteFileError};
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    ffi::CString,
+    panic::{self, catch_unwind, AssertUnwindSafe, PanicHookInfo},
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Relative to DATA search path
+const CRASH_DIRECTORY: &str = "packuwus";
+
+type PanicHook = dyn Fn(&PanicHookInfo) + Sync + Send;
+
+struct CrashHandler {
+    fs: Box<dyn FileSystem>,
+    /// Hook installed before ours, restored on unload
+    previous_hook: Arc<PanicHook>,
+}
+
+// filesystem is only used from game threads, like in `PackUwUs`
+unsafe impl Send for CrashHandler {}
+
+static CRASH_HANDLER: Mutex<Option<CrashHandler>> = Mutex::new(None);
+
+struct PanicReport {
+    message: String,
+    location: String,
+    backtrace: Backtrace,
+}
+
+thread_local! {
+    // stack is already unwound when panic is caught, so backtrace is taken by the hook
+    static LAST_PANIC: RefCell<Option<PanicReport>> = const { RefCell::new(None) };
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Records backtrace of every panic before running previous hook. Crash files are written with
+/// `fs`.
+pub fn install_panic_hook(fs: Box<dyn FileSystem>) {
+    let previous_hook: Arc<PanicHook> = Arc::from(panic::take_hook());
+    let previous = previous_hook.clone();
+
+    panic::set_hook(Box::new(move |info: &PanicHookInfo| {
+        let report = PanicReport {
+            message: panic_message(info.payload()),
+            location: info
+                .location()
+                .map(|location| location.to_string())
+                .unwrap_or_else(|| "unknown location".to_string()),
+            backtrace: Backtrace::force_capture(),
+        };
+
+        LAST_PANIC.with(|last_panic| *last_panic.borrow_mut() = Some(report));
+
+        previous(info);
+    }));
+
+    *CRASH_HANDLER.lock().unwrap_or_else(PoisonError::into_inner) =
+        Some(CrashHandler { fs, previous_hook });
+}
+
+/// Restores hook installed before ours, ours must not outlive the module
+pub fn remove_panic_hook() {
+    let handler = CRASH_HANDLER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+
+    if let Some(CrashHandler { previous_hook, .. }) = handler {
+        panic::set_hook(Box::new(move |info: &PanicHookInfo| previous_hook(info)));
+    }
+}
+
+fn write_crash_file(
+    fs: &dyn FileSystem,
+    detour: &str,
+    args: &str,
+    report: &PanicReport,
+) -> Result<String, WriteFileError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+
+    let path = format!("{}/crash_{}.txt", CRASH_DIRECTORY, timestamp);
+
+    fs.create_dir_hierarchy(
+        CString::new(CRASH_DIRECTORY).unwrap().as_c_str(),
+        Some(c"DATA"),
+    );
+    fs.write_file(
+        CString::new(path.as_str()).unwrap().as_c_str(),
+        Some(c"DATA"),
+        format!(
+            "PackUwUs {} crash report\n\nDetour: {}\nArguments: {}\nPanic: {} at {}\n\nBacktrace:\n{}\n",
+            env!("CARGO_PKG_VERSION"),
+            detour,
+            args,
+            report.message,
+            report.location,
+            report.backtrace
+        )
+        .as_bytes(),
+    )?;
+
+    Ok(path)
+}
+
+/// Runs detour logic. Panic must never unwind into C++ caller, it aborts srcds, so it's caught
+/// and written to crash file with `args` of the call. Returns None if `f` panicked, detour should
+/// fall back to original behaviour then.
+pub fn guard_detour<R>(
+    detour: &str,
+    args: impl FnOnce() -> String,
+    f: impl FnOnce() -> R,
+) -> Option<R> {
+    let payload = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => return Some(result),
+        Err(payload) => payload,
+    };
+
+    let report = LAST_PANIC
+        .with(|last_panic| last_panic.borrow_mut().take())
+        .unwrap_or_else(|| PanicReport {
+            message: panic_message(payload.as_ref()),
+            location: "unknown location".to_string(),
+            backtrace: Backtrace::disabled(),
+        });
+
+    println!(
+        "[PackUwUs] {} panicked, falling back to original: {}",
+        detour, report.message
+    );
+
+    // formatting arguments may panic too, crash file is written anyway
+    let args = catch_unwind(AssertUnwindSafe(args))
+        .unwrap_or_else(|_| "failed to format arguments".to_string());
+
+    let written = CRASH_HANDLER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|handler| write_crash_file(handler.fs.as_ref(), detour, &args, &report));
+
+    match written {
+        Some(Ok(path)) => println!("[PackUwUs] Crash report is written to data/{}", path),
+        Some(Err(err)) => println!("[PackUwUs] Failed to write crash report: {}", err),
+        None => println!("[PackUwUs] Panic hook isn't installed, crash report is not written"),
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::sdk::filesystem::memory::MemoryFileSystem;
+
+    #[test]
+    fn writes_crash_file_to_data() {
+        let fs = MemoryFileSystem::default();
+        let report = PanicReport {
+            message: "oops".to_string(),
+            location: "src/detours.rs:1:1".to_string(),
+            backtrace: Backtrace::disabled(),
+        };
+
+        let path = write_crash_file(&fs, "SendToClient", "client 1", &report).unwrap();
+        let content = String::from_utf8(fs.content(&path).unwrap()).unwrap();
+
+        assert!(path.starts_with("packuwus/crash_") && path.ends_with(".txt"));
+        assert!(content.contains("Detour: SendToClient\nArguments: client 1\n"));
+        assert!(content.contains("Panic: oops at src/detours.rs:1:1"));
+    }
+
+    // panic hook is global, so whole lifecycle is one test
+    #[test]
+    fn guards_detours_and_restores_previous_hook() {
+        static PREVIOUS_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let default_hook = panic::take_hook();
+
+        let test_thread = thread::current().id();
+
+        // other tests panic in parallel, only this thread is counted
+        panic::set_hook(Box::new(move |_| {
+            if thread::current().id() == test_thread {
+                PREVIOUS_CALLS.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+
+        let fs = MemoryFileSystem::default();
+
+        install_panic_hook(Box::new(fs.clone()));
+
+        assert_eq!(guard_detour("Fine", String::new, || 1), Some(1));
+        assert_eq!(
+            guard_detour(
+                "Broken",
+                || "some args".to_string(),
+                || panic!("broken detour")
+            ),
+            None::<()>
+        );
+        // our hook runs the previous one
+        assert_eq!(PREVIOUS_CALLS.load(Ordering::SeqCst), 1);
+
+        let crash_files = fs.paths();
+
+        assert_eq!(crash_files.len(), 1);
+
+        let content = String::from_utf8(fs.content(&crash_files[0]).unwrap()).unwrap();
+
+        assert!(content.contains("Detour: Broken\nArguments: some args\n"));
+        assert!(content.contains("Panic: broken detour at src/crash.rs:"));
+
+        remove_panic_hook();
+
+        let _ = catch_unwind(|| panic!("after unload"));
+
+        assert_eq!(PREVIOUS_CALLS.load(Ordering::SeqCst), 2);
+        assert!(CRASH_HANDLER.lock().unwrap().is_none());
+
+        panic::set_hook(default_hook);
+    }
+}
@@ -1,9 +1,19 @@
 use core::slice;
-use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::{
+    ffi::{c_char, c_int, c_void, CStr},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use retour::static_detour;
 
-use crate::{packuwus::PackUwUs, sdk::luafile::LuaFile, CLIENT_FILES_TABLE, PACKUWUS};
+use crate::{
+    crash::guard_detour,
+    hooks::Capability,
+    lua_functions::report_error,
+    packuwus::{ErrorCode, PackUwUs, RefreshedFile},
+    sdk::luafile::LuaFile,
+    with_packuwus, CLIENT_FILES_TABLE,
+};
 
 static_detour! {
     pub(crate) static GMODDATAPACK_ADDORUPDATEFILE: unsafe extern "C" fn(*const c_void, *mut LuaFile, bool);
@@ -12,6 +22,162 @@ static_detour! {
     pub(crate) static CVENGINESERVER_GMOD_SENDTOCLIENTS: unsafe extern "C" fn (*const c_void, *const c_void, *const c_void, i32);
 }
 
+/// Pack keys are UTF-8, files with other paths are left to vanilla GMod.
+/// Engine paths end at first \0, so they can't contain one.
+fn packable_path(path: &CStr) -> Option<&str> {
+    match path.to_str() {
+        Ok("") => {
+            println!("[PackUwUs] Path is empty, vanilla GMod handles the file");
+
+            None
+        }
+        Ok(str) => Some(str),
+        Err(_) => {
+            println!(
+                "[PackUwUs] Path {} isn't valid UTF-8, vanilla GMod handles the file",
+                path.to_string_lossy()
+            );
+
+            None
+        }
+    }
+}
+
+/// Name of changed file as auto-refresh reports it, extension may or may not be included
+fn changed_file_name(filename: &CStr, file_ext: &CStr) -> Option<String> {
+    let filename = packable_path(filename)?;
+    let file_ext = packable_path(file_ext)?;
+
+    if filename
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| ext == file_ext)
+    {
+        Some(filename.to_string())
+    } else {
+        Some(format!("{}.{}", filename, file_ext))
+    }
+}
+
+/// Splits auto-refresh packet into file path and compressed code
+fn parse_autorefresh_packet(data: &[u8]) -> Option<(&CStr, &[u8])> {
+    // 0x00      (sz: 1)    ??? but 1
+    // 0x01      (sz: \0)   filepath
+    // 0x??+0x01 (sz: 4)    compressed size
+    // 0x??+0x05 (sz: 0x20) hash
+    // 0x??+0x25 (sz: *)    LZMA file content
+
+    let filepath = CStr::from_bytes_until_nul(data.get(1..)?).ok()?;
+
+    let compressed_lzma_code = data.get(1 + filepath.to_bytes_with_nul().len() + 4 + 0x20..)?;
+
+    Some((filepath, compressed_lzma_code))
+}
+
+static LUAFILE_LAYOUT_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Layout is checked for every file, a file that doesn't match is left to vanilla GMod
+fn is_valid_lua_file(file: &LuaFile) -> bool {
+    let Err(err) = file.check_layout() else {
+        return true;
+    };
+
+    if !LUAFILE_LAYOUT_REPORTED.swap(true, Ordering::Relaxed) {
+        println!(
+            "[PackUwUs] LuaFile layout doesn't match ({}), was GMod updated? Files are left unpacked",
+            err
+        );
+    }
+
+    false
+}
+
+/// Returns code Lua replaced contents with, if the file is packed
+unsafe fn handle_added_file(file: &LuaFile, reload: bool) -> Option<Vec<u8>> {
+    if !is_valid_lua_file(file) {
+        return None;
+    }
+
+    let path = packable_path(file.name())?;
+
+    let lua = with_packuwus(|packuwus| packuwus.lua());
+
+    match PackUwUs::handle_pack(lua, path, file.contents()) {
+        Ok((should_pack, new_content)) => {
+            if !should_pack {
+                return None;
+            }
+
+            let rewritten = new_content.clone();
+
+            // reported after the lock is released, error handler is lua
+            let result: Result<(), Box<dyn ErrorCode>> = with_packuwus(|packuwus| {
+                // file could have been removed from pack when it was deleted from disk
+                if reload && packuwus.is_packed(path) {
+                    packuwus
+                        .edit_file(
+                            path,
+                            new_content.unwrap_or_else(|| file.contents().to_vec()),
+                        )
+                        .or_else(|err| Err(Box::new(err) as _))
+                } else {
+                    packuwus
+                        .add_file(path, new_content)
+                        .or_else(|err| Err(Box::new(err) as _))
+                }
+            });
+
+            if let Err(err) = result {
+                println!(
+                    "[PackUwUs] Failed to {} file {} ({}): {}",
+                    if reload { "edit" } else { "add" },
+                    path,
+                    err.code(),
+                    err
+                );
+
+                report_error(lua, err.as_ref(), Some(path));
+            }
+
+            rewritten
+        }
+        Err(err) => {
+            println!(
+                "[PackUwUs] Failed to notify client file {} ({}): {}",
+                path,
+                err.code(),
+                err
+            );
+
+            report_error(lua, &err, Some(path));
+
+            None
+        }
+    }
+}
+
+/// Keeps what auto-refresh packet of the file must carry, it's sent after the file is updated
+unsafe fn remember_refreshed_file(file: &LuaFile, new_code: Option<Vec<u8>>) {
+    if !Capability::AutoRefreshRewrite.is_supported() || !is_valid_lua_file(file) {
+        return;
+    }
+
+    let Some(path) = packable_path(file.name()) else {
+        return;
+    };
+
+    // filled by the original AddOrUpdateFile
+    let Some(compressed) = file.compressed() else {
+        return;
+    };
+
+    let refreshed = RefreshedFile {
+        compressed: compressed.to_vec(),
+        new_code,
+    };
+
+    with_packuwus(|packuwus| packuwus.refreshed_files.insert(path.to_string(), refreshed));
+}
+
 pub(crate) fn new_gmoddatapack_addorupdatefile(
     this: *const c_void,
     file: *mut LuaFile,
@@ -22,41 +188,32 @@ pub(crate) fn new_gmoddatapack_addorupdatefile(
         "GModDataPack::AddOrUpdateFile({:?}, {:?} ({}), {})",
         this,
         &file,
-        Into::<&CStr>::into(unsafe { (*file).name })
-            .to_str()
-            .unwrap(),
+        unsafe { (*file).name() }.to_string_lossy(),
         reload
     );
 
-    unsafe {
-        let path = (*file).name.as_c_str().to_str().unwrap();
-
-        match PACKUWUS
-            .as_ref()
-            .unwrap()
-            .handle_pack(path, &(*file).content.as_c_str().to_string_lossy())
-        {
-            Ok((should_pack, new_content)) => {
-                if should_pack {
-                    if reload {
-                        if let Err(err) = PACKUWUS.as_mut().unwrap().edit_file(
-                            path,
-                            new_content.unwrap_or_else(|| (*file).content.to_string()),
-                        ) {
-                            println!("[PackUwUs] Failed to edit file {}", err);
-                        }
-                    } else {
-                        if let Err(err) = PACKUWUS.as_mut().unwrap().add_file(path, new_content) {
-                            println!("[PackUwUs] Failed to add file: {}", err);
-                        }
-                    }
-                }
-            }
-            Err(err) => println!("[PackUwUs] Failed to notify client file: {}", err),
-        }
-    }
+    let args = || unsafe {
+        format!(
+            "this: {:?}, file: {:?} ({}), reload: {}",
+            this,
+            file,
+            (*file).name().to_string_lossy(),
+            reload
+        )
+    };
+
+    let new_code = guard_detour("GModDataPack::AddOrUpdateFile", args, || unsafe {
+        handle_added_file(&*file, reload)
+    })
+    .flatten();
 
     unsafe { GMODDATAPACK_ADDORUPDATEFILE.call(this, file, reload) }
+
+    if reload {
+        guard_detour("GModDataPack::AddOrUpdateFile", args, || unsafe {
+            remember_refreshed_file(&*file, new_code)
+        });
+    }
 }
 
 pub(crate) fn new_garrysmod_autorefresh_handlechange_lua(
@@ -77,7 +234,72 @@ pub(crate) fn new_garrysmod_autorefresh_handlechange_lua(
         dbg!(CStr::from_ptr(*file_ext).to_string_lossy());
     }
 
-    unsafe { GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA.call(directory, filename, file_ext) }
+    let result =
+        unsafe { GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA.call(directory, filename, file_ext) };
+
+    // deleted files are reported as changes too, drop them from pack if they're gone
+    guard_detour(
+        "GarrysMod::AutoRefresh::HandleChange_Lua",
+        || unsafe {
+            format!(
+                "directory: {:?}, filename: {:?}, file_ext: {:?}",
+                CStr::from_ptr(*directory),
+                CStr::from_ptr(*filename),
+                CStr::from_ptr(*file_ext)
+            )
+        },
+        || unsafe {
+            let Some(filename) =
+                changed_file_name(CStr::from_ptr(*filename), CStr::from_ptr(*file_ext))
+            else {
+                return;
+            };
+
+            for path in with_packuwus(|packuwus| packuwus.remove_deleted_files(&filename)) {
+                println!(
+                    "[PackUwUs] File {} was deleted, removing it from pack",
+                    path
+                );
+            }
+        },
+    );
+
+    result
+}
+
+unsafe fn build_download_packet(
+    file_id: u16,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let Some(filepath) = packable_path(
+        CLIENT_FILES_TABLE
+            .as_ref()
+            .ok_or("Client files table is not set")?
+            .string(file_id as _)
+            .ok_or_else(|| format!("Failed to find filepath by file_id: {}", file_id))?,
+    ) else {
+        return Ok(None);
+    };
+
+    let packed = with_packuwus(|packuwus| {
+        packuwus.is_packed(filepath).then(|| {
+            (
+                packuwus.packed_contents.clone(),
+                packuwus.options.compression.packet_level(),
+            )
+        })
+    });
+
+    let Some((packed_contents, level)) = packed else {
+        return Ok(None);
+    };
+
+    Ok(Some(PackUwUs::build_lua_download_packet(
+        file_id,
+        packed_contents
+            .ok_or("You forgot to set pack content using PackUwUs_SetPackContent function!")?
+            .as_slice(),
+        level,
+    )?))
 }
 
 pub(crate) fn new_cvengineserver_gmod_sendtoclient(
@@ -92,34 +314,19 @@ pub(crate) fn new_cvengineserver_gmod_sendtoclient(
         this, client_id, data, data_len
     );
 
-    unsafe fn build_download_packet(
-        file_id: u16,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        if !PACKUWUS.as_ref().unwrap().is_packed(
-            CLIENT_FILES_TABLE
-                .as_ref()
-                .unwrap()
-                .string(file_id as _)
-                .ok_or_else(|| format!("Failed to find filepath by file_id: {}", file_id))?
-                .to_str()?,
-        ) {
-            return Ok(None);
-        }
-
-        Ok(Some(PackUwUs::build_lua_download_packet(
-            file_id,
-            PACKUWUS
-                .as_ref()
-                .unwrap()
-                .packed_contents
-                .as_ref()
-                .ok_or("You forgot to set pack content using PackUwUs_SetPackContent function!")?
-                .as_str(),
-        )?))
-    }
+    let packet = guard_detour(
+        "CVEngineServer::GMOD_SendToClient",
+        || {
+            format!(
+                "this: {:?}, client_id: {}, data: {:?}, data_len: {}",
+                this, client_id, data, data_len
+            )
+        },
+        || unsafe {
+            if (data as *const u8).read_unaligned() != 4 {
+                return None;
+            }
 
-    unsafe {
-        if (data as *const u8).read_unaligned() == 4 {
             // 0x00 (sz: 1)    GarrysMod::NetworkMessage::LuaFileDownload aka 4
             // 0x01 (sz: 2)    file number
             // 0x03 (sz: 0x20) file content hash
@@ -129,40 +336,104 @@ pub(crate) fn new_cvengineserver_gmod_sendtoclient(
 
             match build_download_packet(file_id) {
                 Ok(packet) => {
-                    if let Some(packet) = packet {
-                        #[cfg(debug_assertions)]
+                    #[cfg(debug_assertions)]
+                    if packet.is_some() {
                         println!(
                             "[PackUwUs] Sending client {} packed file {}",
                             client_id, file_id
                         );
-
-                        return CVENGINESERVER_GMOD_SENDTOCLIENT.call(
-                            this,
-                            client_id,
-                            packet.as_ptr() as _,
-                            (packet.len() * 8) as _,
+                    } else {
+                        println!(
+                            "[PackUwUs] File {} is not packed, sending original to client {}",
+                            file_id, client_id
                         );
                     }
 
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "[PackUwUs] File {} is not packed, sending original content to client {}",
-                        file_id, client_id
-                    );
+                    packet
                 }
                 Err(err) => {
                     println!(
                         "[PackUwUs] Error occured while building download packet: {}",
                         err
-                    )
+                    );
+
+                    None
                 }
             }
-        }
+        },
+    )
+    .flatten();
+
+    if let Some(packet) = packet {
+        return unsafe {
+            CVENGINESERVER_GMOD_SENDTOCLIENT.call(
+                this,
+                client_id,
+                packet.as_ptr() as _,
+                (packet.len() * 8) as _,
+            )
+        };
     }
 
     unsafe { CVENGINESERVER_GMOD_SENDTOCLIENT.call(this, client_id, data, data_len) }
 }
 
+unsafe fn build_autorefresh_packet(data: &[u8]) -> Option<Vec<u8>> {
+    let Some((filepath, compressed_lzma_code)) = parse_autorefresh_packet(data) else {
+        println!("[PackUwUs] Malformed auto-refresh packet, sending it as is");
+
+        return None;
+    };
+
+    let path = packable_path(filepath)?;
+
+    let Some(refreshed) = with_packuwus(|packuwus| packuwus.refreshed_files.remove(path)) else {
+        #[cfg(debug_assertions)]
+        println!(
+            "[PackUwUs] {} wasn't updated by AddOrUpdateFile, sending auto-refresh as is",
+            path
+        );
+
+        return None;
+    };
+
+    // packet carries engine's compressed buffer, so there's no need to decompress it
+    if !compressed_lzma_code.starts_with(&refreshed.compressed) {
+        println!(
+            "[PackUwUs] Auto-refresh packet of {} doesn't match updated file, sending it as is",
+            path
+        );
+
+        return None;
+    }
+
+    let new_lua_code = refreshed.new_code?;
+
+    match PackUwUs::build_lua_autorefresh_packet(
+        filepath,
+        &new_lua_code,
+        with_packuwus(|packuwus| packuwus.options.compression.packet_level()),
+    ) {
+        Ok(packet) => {
+            #[cfg(debug_assertions)]
+            println!("[PackUwUs] Auto-refresh {}", path);
+
+            Some(packet)
+        }
+        Err(err) => {
+            println!(
+                "[PackUwUs] Failed to build autorefresh packet ({}): {}",
+                err.code(),
+                err
+            );
+
+            report_error(with_packuwus(|packuwus| packuwus.lua()), &err, Some(path));
+
+            None
+        }
+    }
+}
+
 pub(crate) fn new_cvengineserver_gmod_sendtoclients(
     this: *const c_void,
     filter: *const c_void,
@@ -175,101 +446,174 @@ pub(crate) fn new_cvengineserver_gmod_sendtoclients(
         this, filter, data, data_len
     );
 
-    unsafe fn try_get_new_lua_code(
-        filepath: &CStr,
-        compressed_lzma_code: &[u8],
-    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let filepath = filepath.to_str()?;
-
-        let original_lua_code = CString::from_vec_with_nul(
-            gmod_lzma::decompress(compressed_lzma_code).or_else(|lzma_errnum| {
-                Err(format!(
-                    "Failed to decompress: status code is {}",
-                    lzma_errnum
-                ))
-            })?,
-        )?;
-
-        let (should_pack, new_code) = PACKUWUS
-            .as_ref()
-            .unwrap()
-            .handle_pack(filepath, &original_lua_code.to_string_lossy())?;
+    let packet = guard_detour(
+        "CVEngineServer::GMOD_SendToClient (all clients)",
+        || {
+            format!(
+                "this: {:?}, filter: {:?}, data: {:?}, data_len: {}",
+                this, filter, data, data_len
+            )
+        },
+        || unsafe {
+            let data = slice::from_raw_parts(data as *const u8, (data_len / 8) as _);
+
+            if data.first() != Some(&1) || !Capability::AutoRefreshRewrite.is_supported() {
+                return None;
+            }
 
-        let has_new_code = new_code.is_some();
+            //hexdump(data);
+
+            build_autorefresh_packet(data)
+        },
+    )
+    .flatten();
+
+    if let Some(packet) = packet {
+        return unsafe {
+            CVENGINESERVER_GMOD_SENDTOCLIENTS.call(
+                this,
+                filter,
+                packet.as_ptr() as _,
+                (packet.len() * 8) as _,
+            )
+        };
+    }
 
-        let code_to_save =
-            new_code.unwrap_or_else(|| original_lua_code.to_string_lossy().to_string());
+    unsafe { CVENGINESERVER_GMOD_SENDTOCLIENTS.call(this, filter, data, data_len) }
+}
 
-        if let Err(err) = PACKUWUS
-            .as_mut()
-            .unwrap()
-            .edit_file(filepath, code_to_save.clone())
-        {
-            println!("[PackUwUs] packUwUs edit file failed: {}", err);
-        }
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
 
-        if should_pack && has_new_code {
-            return Ok(Some(code_to_save));
-        }
+    use super::*;
 
-        Ok(None)
+    fn c_string(bytes: &[u8]) -> CString {
+        CString::new(bytes).unwrap()
     }
 
-    unsafe {
-        let data = data as *const u8;
+    #[test]
+    fn packs_utf8_paths() {
+        assert_eq!(
+            packable_path(c"lua/autorun/client/cl_init.lua"),
+            Some("lua/autorun/client/cl_init.lua")
+        );
+        assert_eq!(
+            packable_path(c"lua/ünïcödé/файл.lua"),
+            Some("lua/ünïcödé/файл.lua")
+        );
+    }
 
-        if data.read_unaligned() == 1 {
-            // 0x00      (sz: 1)    ??? but 1
-            // 0x01      (sz: \0)   filepath
-            // 0x??+0x01 (sz: 4)    compressed size
-            // 0x??+0x05 (sz: 0x20) hash
-            // 0x??+0x25 (sz: *)    LZMA file content
+    #[test]
+    fn leaves_odd_paths_to_vanilla() {
+        assert_eq!(packable_path(c""), None);
+        assert_eq!(packable_path(&c_string(b"lua/\xff\xfe.lua")), None);
+        // cut off multibyte character
+        assert_eq!(packable_path(&c_string(b"lua/\xd1.lua")), None);
+    }
 
-            //hexdump(slice::from_raw_parts(data, (data_len / 8) as _));
+    #[test]
+    fn packs_very_long_paths() {
+        let path = format!("lua/{}.lua", "a/".repeat(4096));
+        let c_path = c_string(path.as_bytes());
 
-            let filepath = CStr::from_ptr(data.byte_offset(1) as _);
+        assert_eq!(packable_path(&c_path), Some(path.as_str()));
+    }
 
-            let compressed_lzma_code = data
-                .byte_offset(0x01)
-                .byte_offset(filepath.to_bytes_with_nul().len() as _)
-                .byte_offset(4)
-                .byte_offset(0x20);
+    #[test]
+    fn paths_end_at_nul() {
+        // engine strings are C strings, anything after \0 is never seen
+        let path = CStr::from_bytes_until_nul(b"lua/a.lua\0.hidden.lua\0").unwrap();
 
-            let compressed_lzma_code = slice::from_raw_parts(
-                compressed_lzma_code,
-                (((data_len / 8) as usize) - 1 - filepath.to_bytes_with_nul().len()) as _,
-            );
+        assert_eq!(packable_path(path), Some("lua/a.lua"));
+        assert!(CString::new(b"lua/a\0.lua".to_vec()).is_err());
+    }
 
-            match try_get_new_lua_code(filepath, compressed_lzma_code) {
-                Ok(new_lua_code) => {
-                    if let Some(new_lua_code) = new_lua_code {
-                        match PackUwUs::build_lua_autorefresh_packet(
-                            filepath.to_str().unwrap(),
-                            new_lua_code.as_str(),
-                        ) {
-                            Ok(packet) => {
-                                #[cfg(debug_assertions)]
-                                println!("[PackUwUs] Auto-refresh {}", filepath.to_string_lossy());
-
-                                return CVENGINESERVER_GMOD_SENDTOCLIENTS.call(
-                                    this,
-                                    filter,
-                                    packet.as_ptr() as _,
-                                    (packet.len() * 8) as _,
-                                );
-                            }
-                            Err(err) => {
-                                println!("[PackUwUs] Failed to build autorefresh packet: {}", err)
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    println!("Error occured in try_get_new_lua_code: {}", err)
-                }
-            }
-        }
+    #[test]
+    fn names_changed_files() {
+        assert_eq!(
+            changed_file_name(c"cl_init", c"lua"),
+            Some("cl_init.lua".to_string())
+        );
+        assert_eq!(
+            changed_file_name(c"cl_init.lua", c"lua"),
+            Some("cl_init.lua".to_string())
+        );
+        assert_eq!(
+            changed_file_name(c"cl_init.txt", c"lua"),
+            Some("cl_init.txt.lua".to_string())
+        );
+        assert_eq!(
+            changed_file_name(c"файл", c"lua"),
+            Some("файл.lua".to_string())
+        );
     }
 
-    unsafe { CVENGINESERVER_GMOD_SENDTOCLIENTS.call(this, filter, data, data_len) }
+    #[test]
+    fn skips_odd_changed_file_names() {
+        assert_eq!(changed_file_name(&c_string(b"\xff"), c"lua"), None);
+        assert_eq!(changed_file_name(c"cl_init", &c_string(b"\xff")), None);
+        assert_eq!(changed_file_name(c"", c"lua"), None);
+        assert_eq!(changed_file_name(c"cl_init", c""), None);
+
+        let long_name = "a".repeat(10000);
+
+        assert_eq!(
+            changed_file_name(&c_string(long_name.as_bytes()), c"lua"),
+            Some(format!("{}.lua", long_name))
+        );
+    }
+
+    fn autorefresh_packet(path: &[u8], code: &[u8]) -> Vec<u8> {
+        let mut packet = vec![1];
+
+        packet.extend_from_slice(path);
+        packet.push(0);
+        packet.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&[0xAB; 0x20]);
+        packet.extend_from_slice(code);
+
+        packet
+    }
+
+    #[test]
+    fn parses_autorefresh_packets() {
+        let packet = autorefresh_packet(b"lua/a.lua", b"code");
+        let (path, code) = parse_autorefresh_packet(&packet).unwrap();
+
+        assert_eq!(path, c"lua/a.lua");
+        assert_eq!(code, b"code");
+
+        let packet = autorefresh_packet(b"lua/\xff.lua", b"");
+        let (path, code) = parse_autorefresh_packet(&packet).unwrap();
+
+        assert_eq!(path.to_bytes(), b"lua/\xff.lua");
+        assert!(code.is_empty());
+        assert_eq!(packable_path(path), None);
+    }
+
+    #[test]
+    fn rejects_malformed_autorefresh_packets() {
+        assert!(parse_autorefresh_packet(&[]).is_none());
+        assert!(parse_autorefresh_packet(&[1]).is_none());
+        // path isn't terminated
+        assert!(parse_autorefresh_packet(b"\x01lua/a.lua").is_none());
+
+        // header is cut off
+        let packet = autorefresh_packet(b"lua/a.lua", b"");
+
+        assert!(parse_autorefresh_packet(&packet[..packet.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn autorefresh_path_ends_at_first_nul() {
+        // \0 inside path makes the rest look like header, it must not be read past the packet
+        let packet = autorefresh_packet(b"lua/a\0.lua", b"");
+
+        assert!(parse_autorefresh_packet(&packet).is_some_and(|(path, _)| path == c"lua/a"));
+
+        let short = autorefresh_packet(b"a\0", b"");
+
+        assert!(parse_autorefresh_packet(&short[..short.len() - 2]).is_none());
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+const ESCAPE: u8 = 0x01;
+const MAX_ENTRIES: usize = 0xFF;
+const MIN_TOKEN_LEN: usize = 4;
+
+/// Substitution dictionary trained on packed files. Frequent tokens like `hook.Add` or
+/// `net.Receive` are replaced by `\x01 <index>`, literal `\x01` is written as `\x01 \x00`.
+/// Every file stays independently decodable, client only needs dictionary entries.
+/// Clients decompress with `util.Decompress`, which takes no preset dictionary, so this runs
+/// before LZMA instead of being LZMA dictionary itself.
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub entries: Vec<Vec<u8>>,
+    index: HashMap<Vec<u8>, u8>,
+}
+
+fn is_ident_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_'
+}
+
+fn is_ident(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+// identifiers joined with . or :, e.g. `vgui.Create` or `self:SetSize`
+fn token_end(data: &[u8], start: usize) -> usize {
+    let mut end = start;
+
+    loop {
+        while end < data.len() && is_ident(data[end]) {
+            end += 1;
+        }
+
+        if end + 1 < data.len()
+            && (data[end] == b'.' || data[end] == b':')
+            && is_ident_start(data[end + 1])
+        {
+            end += 1;
+        } else {
+            return end;
+        }
+    }
+}
+
+fn for_each_token<'a>(data: &'a [u8], mut f: impl FnMut(Result<&'a [u8], u8>)) {
+    let mut i = 0;
+
+    while i < data.len() {
+        if is_ident_start(data[i]) {
+            let end = token_end(data, i);
+
+            f(Ok(&data[i..end]));
+
+            i = end;
+        } else {
+            f(Err(data[i]));
+
+            i += 1;
+        }
+    }
+}
+
+impl Dictionary {
+    pub fn new(entries: Vec<Vec<u8>>) -> Dictionary {
+        let index = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.clone(), (i + 1) as u8))
+            .collect();
+
+        Dictionary { entries, index }
+    }
+
+    /// Picks tokens that save the most bytes over whole corpus. Encoded contents and chunk
+    /// names depend on the dictionary, so it's trained once and kept across packs.
+    pub fn train<'a>(contents: impl Iterator<Item = &'a [u8]>) -> Dictionary {
+        let mut counts: HashMap<&[u8], usize> = HashMap::new();
+
+        for content in contents {
+            for_each_token(content, |token| {
+                if let Ok(token) = token {
+                    if token.len() >= MIN_TOKEN_LEN {
+                        *counts.entry(token).or_default() += 1;
+                    }
+                }
+            });
+        }
+
+        let mut scored: Vec<(usize, &[u8])> = counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(token, count)| (count * (token.len() - 2), token))
+            .collect();
+
+        // sorted by token as well, so same corpus always gives same dictionary
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        scored.truncate(MAX_ENTRIES);
+
+        Dictionary::new(
+            scored
+                .into_iter()
+                .map(|(_, token)| token.to_vec())
+                .collect(),
+        )
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(data.len());
+
+        for_each_token(data, |token| match token {
+            Ok(token) => match self.index.get(token) {
+                Some(index) => buf.extend_from_slice(&[ESCAPE, *index]),
+                None => buf.extend_from_slice(token),
+            },
+            Err(ESCAPE) => buf.extend_from_slice(&[ESCAPE, 0]),
+            Err(b) => buf.push(b),
+        });
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [&[u8]; 3] = [
+        b"hook.Add(\"Think\", \"a\", function() net.Start(\"a\") net.SendToServer() end)",
+        b"hook.Add(\"HUDPaint\", \"b\", function() end) net.Receive(\"a\", function() end)",
+        b"local panel = vgui.Create(\"DFrame\") net.Start(\"b\") hook.Add(\"c\", \"c\", print)",
+    ];
+
+    #[test]
+    fn training_ignores_file_order() {
+        let a = Dictionary::train(FILES.into_iter());
+        let b = Dictionary::train(FILES.into_iter().rev());
+
+        assert_eq!(a.entries, b.entries);
+        assert!(a.entries.contains(&b"hook.Add".to_vec()));
+        assert!(a.entries.contains(&b"net.Start".to_vec()));
+    }
+
+    #[test]
+    fn encodes_tokens_and_escapes() {
+        let dictionary = Dictionary::new(vec![b"hook.Add".to_vec()]);
+
+        assert_eq!(dictionary.encode(b"hook.Add(x)"), b"\x01\x01(x)");
+        assert_eq!(dictionary.encode(b"hook.Remove"), b"hook.Remove");
+        assert_eq!(dictionary.encode(b"a\x01b"), b"a\x01\x00b");
+    }
+}
@@ -0,0 +1,302 @@
+use std::{ffi::c_void, mem::transmute};
+
+use crate::{
+    detours::{
+        new_cvengineserver_gmod_sendtoclient, new_cvengineserver_gmod_sendtoclients,
+        new_garrysmod_autorefresh_handlechange_lua, new_gmoddatapack_addorupdatefile,
+        CVENGINESERVER_GMOD_SENDTOCLIENT, CVENGINESERVER_GMOD_SENDTOCLIENTS,
+        GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA, GMODDATAPACK_ADDORUPDATEFILE,
+    },
+    module::{Module, ResolveError},
+    signature::{NamedSignature, Signature},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum HookError {
+    #[error("Failed to resolve: {0}")]
+    Resolve(ResolveError),
+    #[error("Failed to initialize hook: {0}")]
+    Initialize(retour::Error),
+    #[error("Failed to enable hook: {0}")]
+    Enable(retour::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookModule {
+    Server,
+    Engine,
+}
+
+pub struct Hook {
+    pub name: &'static str,
+    pub module: HookModule,
+    pub symbol: &'static str,
+    /// Startup fails without required hooks, optional ones only disable their capabilities
+    pub required: bool,
+    install: fn(*const c_void) -> Result<(), HookError>,
+    disable: fn() -> Result<(), retour::Error>,
+    is_enabled: fn() -> bool,
+}
+
+pub struct HookFailure {
+    pub hook: &'static Hook,
+    pub error: HookError,
+}
+
+#[derive(Default)]
+pub struct HookFailures {
+    /// Module can't work without these, every one of them is reported at once
+    pub required: Vec<HookFailure>,
+    pub optional: Vec<HookFailure>,
+}
+
+macro_rules! hook {
+    ($name:expr, $module:expr, $symbol:expr, $required:expr, $detour:ident, $new_fn:ident) => {
+        Hook {
+            name: $name,
+            module: $module,
+            symbol: $symbol,
+            required: $required,
+            install: |address| unsafe {
+                $detour
+                    .initialize(transmute(address), $new_fn)
+                    .or_else(|err| Err(HookError::Initialize(err)))?;
+
+                $detour.enable().or_else(|err| Err(HookError::Enable(err)))
+            },
+            disable: || unsafe { $detour.disable() },
+            is_enabled: || $detour.is_enabled(),
+        }
+    };
+}
+
+pub static ADDORUPDATEFILE_HOOK: Hook = hook!(
+    "GModDataPack::AddOrUpdateFile",
+    HookModule::Server,
+    "_ZN12GModDataPack15AddOrUpdateFileEP7LuaFileb",
+    true,
+    GMODDATAPACK_ADDORUPDATEFILE,
+    new_gmoddatapack_addorupdatefile
+);
+
+pub static HANDLECHANGE_LUA_HOOK: Hook = hook!(
+    "GarrysMod::AutoRefresh::HandleChange_Lua",
+    HookModule::Server,
+    "_ZN9GarrysMod11AutoRefresh16HandleChange_LuaERKSsS2_S2_",
+    false,
+    GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA,
+    new_garrysmod_autorefresh_handlechange_lua
+);
+
+pub static SENDTOCLIENTS_HOOK: Hook = hook!(
+    "CVEngineServer::GMOD_SendToClient (all clients)",
+    HookModule::Engine,
+    "_ZN14CVEngineServer17GMOD_SendToClientEP16IRecipientFilterPvi",
+    false,
+    CVENGINESERVER_GMOD_SENDTOCLIENTS,
+    new_cvengineserver_gmod_sendtoclients
+);
+
+pub static SENDTOCLIENT_HOOK: Hook = hook!(
+    "CVEngineServer::GMOD_SendToClient",
+    HookModule::Engine,
+    "_ZN14CVEngineServer17GMOD_SendToClientEiPvi",
+    false,
+    CVENGINESERVER_GMOD_SENDTOCLIENT,
+    new_cvengineserver_gmod_sendtoclient
+);
+
+/// Every detour PackUwUs installs. Hooks are installed in this order and disabled in reverse.
+pub static HOOKS: &[&Hook] = &[
+    &ADDORUPDATEFILE_HOOK,
+    &HANDLECHANGE_LUA_HOOK,
+    &SENDTOCLIENTS_HOOK,
+    &SENDTOCLIENT_HOOK,
+];
+
+/// Feature that only works if all of its hooks are installed. Missing hooks disable
+/// just the features depending on them, everything else falls back to vanilla behaviour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    /// Collecting client files and building packs
+    Packing,
+    /// Replacing client file downloads with unpack stubs
+    DownloadRewrite,
+    /// Sending trimmed code on auto-refresh
+    AutoRefreshRewrite,
+    /// Dropping files deleted from disk as soon as auto-refresh reports them,
+    /// without it they're dropped by periodic reconcile only
+    DeletionTracking,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [
+        Capability::Packing,
+        Capability::DownloadRewrite,
+        Capability::AutoRefreshRewrite,
+        Capability::DeletionTracking,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Packing => "packing",
+            Capability::DownloadRewrite => "download_rewrite",
+            Capability::AutoRefreshRewrite => "autorefresh_rewrite",
+            Capability::DeletionTracking => "deletion_tracking",
+        }
+    }
+
+    fn hooks(&self) -> Vec<&'static Hook> {
+        match self {
+            Capability::Packing => vec![&ADDORUPDATEFILE_HOOK],
+            Capability::DownloadRewrite => vec![&SENDTOCLIENT_HOOK],
+            Capability::AutoRefreshRewrite => vec![&SENDTOCLIENTS_HOOK],
+            Capability::DeletionTracking => vec![&HANDLECHANGE_LUA_HOOK],
+        }
+    }
+
+    fn dependencies(&self) -> &'static [Capability] {
+        match self {
+            Capability::Packing => &[],
+            Capability::DownloadRewrite
+            | Capability::AutoRefreshRewrite
+            | Capability::DeletionTracking => &[Capability::Packing],
+        }
+    }
+
+    /// Names of hooks this capability misses, including ones of its dependencies
+    pub fn missing_hooks(&self) -> Vec<&'static str> {
+        let mut missing: Vec<&'static str> = self
+            .dependencies()
+            .iter()
+            .flat_map(|dependency| dependency.missing_hooks())
+            .collect();
+
+        missing.extend(
+            self.hooks()
+                .iter()
+                .filter(|hook| !hook.is_enabled())
+                .map(|hook| hook.name),
+        );
+
+        missing
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.missing_hooks().is_empty()
+    }
+}
+
+impl Hook {
+    pub fn is_enabled(&self) -> bool {
+        (self.is_enabled)()
+    }
+
+    /// There are no built-in signatures, a pattern can't be shipped until it's verified against
+    /// real server binaries. Fallbacks come only from signatures file.
+    fn install_from(&self, module: &Module, extra: &[NamedSignature]) -> Result<(), HookError> {
+        let signatures: Vec<Signature> = extra
+            .iter()
+            .filter(|named| named.hook == self.name)
+            .map(|named| named.signature.clone())
+            .collect();
+
+        let (address, resolution) = module
+            .resolve(self.symbol, &signatures)
+            .or_else(|err| Err(HookError::Resolve(err)))?;
+
+        println!("[PackUwUs] Resolved {} by {}", self.name, resolution);
+
+        (self.install)(address)
+    }
+}
+
+/// Installs every hook, failures are collected instead of stopping at the first one.
+/// `extra` are fallback signatures from signatures file, tried when symbol is missing.
+pub fn install_hooks(
+    server_srv: &Module,
+    engine_srv: &Module,
+    extra: &[NamedSignature],
+) -> HookFailures {
+    let mut failures = HookFailures::default();
+
+    for hook in HOOKS {
+        let module = match hook.module {
+            HookModule::Server => server_srv,
+            HookModule::Engine => engine_srv,
+        };
+
+        let Err(error) = hook.install_from(module, extra) else {
+            continue;
+        };
+
+        let failure = HookFailure { hook, error };
+
+        if hook.required {
+            failures.required.push(failure);
+        } else {
+            failures.optional.push(failure);
+        }
+    }
+
+    failures
+}
+
+pub fn disable_hooks() {
+    for hook in HOOKS.iter().rev() {
+        if !hook.is_enabled() {
+            continue;
+        }
+
+        if let Err(err) = (hook.disable)() {
+            println!("[PackUwUs] Failed to disable {}: {}", hook.name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn every_hook_belongs_to_capability() {
+        for hook in HOOKS {
+            assert!(
+                Capability::ALL.iter().any(|capability| capability
+                    .hooks()
+                    .iter()
+                    .any(|capability_hook| ptr::eq(*capability_hook, *hook))),
+                "{} disables nothing when it's missing",
+                hook.name
+            );
+        }
+    }
+
+    #[test]
+    fn only_packing_hooks_are_required() {
+        // optional capabilities depend on packing, so its hooks can't be skipped
+        for hook in HOOKS {
+            assert_eq!(
+                hook.required,
+                Capability::Packing
+                    .hooks()
+                    .iter()
+                    .any(|packing_hook| ptr::eq(*packing_hook, *hook)),
+                "{}",
+                hook.name
+            );
+        }
+    }
+
+    #[test]
+    fn missing_hooks_include_dependencies() {
+        // nothing is installed in tests
+        assert_eq!(
+            Capability::DeletionTracking.missing_hooks(),
+            [ADDORUPDATEFILE_HOOK.name, HANDLECHANGE_LUA_HOOK.name]
+        );
+        assert!(!Capability::Packing.is_supported());
+    }
+}
@@ -1,38 +1,114 @@
 #![feature(hasher_prefixfree_extras)]
 
+mod compression;
+mod crash;
 mod detours;
+mod dictionary;
+mod hooks;
 mod lua_functions;
 mod module;
+mod pack;
 mod packuwus;
 mod sdk;
+mod signature;
 
-use detours::{
-    new_cvengineserver_gmod_sendtoclient, new_cvengineserver_gmod_sendtoclients,
-    new_garrysmod_autorefresh_handlechange_lua, new_gmoddatapack_addorupdatefile,
-    CVENGINESERVER_GMOD_SENDTOCLIENT, CVENGINESERVER_GMOD_SENDTOCLIENTS,
-    GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA, GMODDATAPACK_ADDORUPDATEFILE,
-};
+use crash::{install_panic_hook, remove_panic_hook};
 use gmod::{
     gmod13_close, gmod13_open,
-    lua::{State, LUA_GLOBALSINDEX},
+    lua::{LuaFunction, LuaString, State, LUA_GLOBALSINDEX},
     lua_string,
 };
-use lua_functions::{pack_async, pack_sync, set_pack_content};
+use hooks::{disable_hooks, install_hooks, Capability};
+use lua_functions::{
+    add_virtual_file, benchmark_layouts, cancel_pack, capabilities, get_file, is_packed,
+    list_files, pack_async, pack_sync, reconcile_report, remove_file, remove_missing_files,
+    retrain_dictionary, set_option, set_pack_content, shutdown,
+};
 use module::Module;
 use packuwus::PackUwUs;
 use procfs::process::Process;
 use sdk::{
-    filesystem::WrappedFileSystem,
+    filesystem::{FileSystem, WrappedFileSystem},
     networkstringtable::WrappedNetworkStringTable,
     networkstringtablecontainer::{
         NetworkStringTableContainer, WrappedNetworkStringTableContainer,
     },
 };
-use std::{ffi::c_void, mem::transmute};
+use signature::{parse_signature_file, NamedSignature};
+use std::{
+    ffi::CStr,
+    sync::{Mutex, PoisonError},
+};
 
-static mut PACKUWUS: Option<PackUwUs> = None;
+static PACKUWUS: Mutex<Option<PackUwUs>> = Mutex::new(None);
 static mut CLIENT_FILES_TABLE: Option<WrappedNetworkStringTable> = None;
 
+const LUA_FUNCTIONS: &[(LuaString, LuaFunction)] = &[
+    (lua_string!("PackUwUs_PackSync"), pack_sync),
+    (lua_string!("PackUwUs_PackAsync"), pack_async),
+    (lua_string!("PackUwUs_CancelPack"), cancel_pack),
+    (lua_string!("PackUwUs_SetPackContent"), set_pack_content),
+    (lua_string!("PackUwUs_SetOption"), set_option),
+    (lua_string!("PackUwUs_BenchmarkLayouts"), benchmark_layouts),
+    (lua_string!("PackUwUs_Capabilities"), capabilities),
+    (lua_string!("PackUwUs_ListFiles"), list_files),
+    (lua_string!("PackUwUs_GetFile"), get_file),
+    (lua_string!("PackUwUs_IsPacked"), is_packed),
+    (lua_string!("PackUwUs_AddVirtualFile"), add_virtual_file),
+    (lua_string!("PackUwUs_RemoveFile"), remove_file),
+    (
+        lua_string!("PackUwUs_RemoveMissingFiles"),
+        remove_missing_files,
+    ),
+    (lua_string!("PackUwUs_ReconcileReport"), reconcile_report),
+    (
+        lua_string!("PackUwUs_RetrainDictionary"),
+        retrain_dictionary,
+    ),
+];
+
+/// Runs `f` with module state locked. Don't call into Lua inside, it may call back into the
+/// module and deadlock, and `lua.error` would leave the state locked forever.
+pub(crate) fn with_packuwus<R>(f: impl FnOnce(&mut PackUwUs) -> R) -> R {
+    // panics in detours are caught, state must stay usable after them
+    f(PACKUWUS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+        .expect("PackUwUs is not initialized"))
+}
+
+/// Fallback hook signatures, see [`parse_signature_file`]. Relative to DATA search path.
+const SIGNATURES_FILE: &CStr = c"packuwus/signatures.txt";
+
+fn read_signatures(fs: &dyn FileSystem) -> Vec<NamedSignature> {
+    // file is optional
+    let Ok(content) = fs.read_file(SIGNATURES_FILE, Some(c"DATA")) else {
+        return vec![];
+    };
+
+    match parse_signature_file(&String::from_utf8_lossy(&content)) {
+        Ok(signatures) => {
+            println!(
+                "[PackUwUs] Loaded {} signatures from data/{}",
+                signatures.len(),
+                SIGNATURES_FILE.to_string_lossy()
+            );
+
+            signatures
+        }
+        Err(err) => {
+            println!(
+                "[PackUwUs] Ignoring data/{}: {}",
+                SIGNATURES_FILE.to_string_lossy(),
+                err
+            );
+
+            vec![]
+        }
+    }
+}
+
 #[gmod13_open]
 fn gmod13_open(lua: State) -> i32 {
     let this_proc = match Process::myself() {
@@ -50,6 +126,14 @@ fn gmod13_open(lua: State) -> i32 {
         Err(err) => unsafe { lua.error(format!("Failed to get engine_srv.so: {}", err)) },
     };
 
+    for module in [&server_srv, &engine_srv] {
+        println!(
+            "[PackUwUs] Indexed {} symbols of {}",
+            module.symbols_count(),
+            module.path.display()
+        );
+    }
+
     let network_string_table_container = unsafe {
         match engine_srv.interface::<NetworkStringTableContainer>("VEngineServerStringTable001") {
             Ok(ptr) => Some(WrappedNetworkStringTableContainer(ptr)),
@@ -93,184 +177,92 @@ fn gmod13_open(lua: State) -> i32 {
         }
     };
 
-    let gmoddatapack_addorupdatefile =
-        match server_srv.symbol("_ZN12GModDataPack15AddOrUpdateFileEP7LuaFileb") {
-            Ok(sym) => sym,
-            Err(err) => unsafe {
-                lua.error(format!(
-                    "Failed to get GModDataPack::AddOrUpdateFile: {}",
-                    err
-                ))
-            },
-        };
+    let failures = install_hooks(&server_srv, &engine_srv, &read_signatures(&fs));
 
-    unsafe {
-        if let Err(err) = GMODDATAPACK_ADDORUPDATEFILE.initialize(
-            transmute(gmoddatapack_addorupdatefile as *const c_void),
-            new_gmoddatapack_addorupdatefile,
-        ) {
-            lua.error(format!(
-                "Failed to initialize GModDataPack::AddOrUpdateFile hook: {}",
-                err
-            ))
-        }
-
-        if let Err(err) = GMODDATAPACK_ADDORUPDATEFILE.enable() {
-            lua.error(format!(
-                "Failed to enable GModDataPack::AddOrUpdateFile hook: {}",
-                err
-            ))
-        }
+    for failure in failures.required.iter() {
+        println!(
+            "[PackUwUs] Failed to hook {}: {}",
+            failure.hook.name, failure.error
+        );
     }
 
-    let garrysmod_autorefresh_handlechange_lua =
-        match server_srv.symbol("_ZN9GarrysMod11AutoRefresh16HandleChange_LuaERKSsS2_S2_") {
-            Ok(sym) => sym,
-            Err(err) => unsafe {
-                lua.error(format!(
-                    "Failed to get GarrysMod::AutoRefresh::HandleChange_Lua: {}",
-                    err
-                ))
-            },
-        };
+    if !failures.required.is_empty() {
+        disable_hooks();
 
-    unsafe {
-        if let Err(err) = GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA.initialize(
-            transmute(garrysmod_autorefresh_handlechange_lua as *const c_void),
-            new_garrysmod_autorefresh_handlechange_lua,
-        ) {
-            lua.error(format!(
-                "Failed to initialize GarrysMod::AutoRefresh::HandleChange_Lua hook: {}",
-                err
-            ))
-        }
+        let required_failures: Vec<String> = failures
+            .required
+            .iter()
+            .map(|failure| format!("{}: {}", failure.hook.name, failure.error))
+            .collect();
 
-        if let Err(err) = GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA.enable() {
+        unsafe {
             lua.error(format!(
-                "Failed to enable GarrysMod::AutoRefresh::HandleChange_Lua hook: {}",
-                err
+                "Failed to install required hooks:\n{}",
+                required_failures.join("\n")
             ))
         }
     }
 
-    let cvengineserver_gmod_sendtoclients =
-        match engine_srv.symbol("_ZN14CVEngineServer17GMOD_SendToClientEP16IRecipientFilterPvi") {
-            Ok(sym) => sym,
-            Err(err) => unsafe {
-                lua.error(format!(
-                    "Failed to get CVEngineServer::GMOD_SendToClient (all clients): {}",
-                    err
-                ))
-            },
-        };
-
-    unsafe {
-        if let Err(err) = CVENGINESERVER_GMOD_SENDTOCLIENTS.initialize(
-            transmute(cvengineserver_gmod_sendtoclients as *const c_void),
-            new_cvengineserver_gmod_sendtoclients,
-        ) {
-            lua.error(format!(
-                "Failed to initialize CVEngineServer::GMOD_SendToClient (all clients) hook: {}",
-                err
-            ))
-        }
-
-        if let Err(err) = CVENGINESERVER_GMOD_SENDTOCLIENTS.enable() {
-            lua.error(format!(
-                "Failed to enable CVEngineServer::GMOD_SendToClient (all clients) hook: {}",
-                err
-            ))
-        }
+    // missing optional hooks only disable capabilities depending on them
+    for failure in failures.optional.iter() {
+        println!(
+            "[PackUwUs] Skipping optional hook {}: {}",
+            failure.hook.name, failure.error
+        );
     }
 
-    let cvengineserver_gmod_sendtoclient =
-        match engine_srv.symbol("_ZN14CVEngineServer17GMOD_SendToClientEiPvi") {
-            Ok(sym) => sym,
-            Err(err) => unsafe {
-                lua.error(format!(
-                    "Failed to get CVEngineServer::GMOD_SendToClient: {}",
-                    err
-                ))
-            },
-        };
-
-    unsafe {
-        if let Err(err) = CVENGINESERVER_GMOD_SENDTOCLIENT.initialize(
-            transmute(cvengineserver_gmod_sendtoclient as *const c_void),
-            new_cvengineserver_gmod_sendtoclient,
-        ) {
-            lua.error(format!(
-                "Failed to initialize CVEngineServer::GMOD_SendToClient hook: {}",
-                err
-            ))
-        }
-
-        if let Err(err) = CVENGINESERVER_GMOD_SENDTOCLIENT.enable() {
-            lua.error(format!(
-                "Failed to enable CVEngineServer::GMOD_SendToClient hook: {}",
-                err
-            ))
+    for capability in Capability::ALL {
+        if !capability.is_supported() {
+            println!(
+                "[PackUwUs] {} is disabled, missing hooks: {}",
+                capability.name(),
+                capability.missing_hooks().join(", ")
+            );
         }
     }
 
-    unsafe { PACKUWUS = Some(PackUwUs::new(lua, fs, downloadables, client_lua_files)) }
+    // nothing fails after this point, failed open must not leave the hook behind
+    install_panic_hook(Box::new(fs));
 
-    unsafe {
-        lua.push_function(pack_sync);
-        lua.set_field(LUA_GLOBALSINDEX, lua_string!("PackUwUs_PackSync"));
-
-        lua.push_function(pack_async);
-        lua.set_field(LUA_GLOBALSINDEX, lua_string!("PackUwUs_PackAsync"));
+    *PACKUWUS.lock().unwrap() = Some(PackUwUs::new(
+        lua,
+        Box::new(fs),
+        downloadables,
+        client_lua_files,
+    ));
 
-        lua.push_function(set_pack_content);
-        lua.set_field(LUA_GLOBALSINDEX, lua_string!("PackUwUs_SetPackContent"));
+    unsafe {
+        for (name, function) in LUA_FUNCTIONS {
+            lua.push_function(*function);
+            lua.set_field(LUA_GLOBALSINDEX, *name);
+        }
     }
 
     0
 }
 
-#[allow(unused_variables)]
 #[gmod13_close]
 fn gmod13_close(lua: State) -> i32 {
-    if let Err(err) = unsafe { GMODDATAPACK_ADDORUPDATEFILE.disable() } {
-        println!(
-            "[PackUwUs] Failed to disable GModDataPack::AddOrUpdateFile: {}",
-            err
-        );
-    }
+    println!("[PackUwUs] Unloading...");
 
-    if let Err(err) = unsafe { GARRYSMOD_AUTOREFRESH_HANDLECHANGE_LUA.disable() } {
-        println!(
-            "[PackUwUs] Failed to disable GarrysMod::AutoRefresh::HandleChange_Lua: {}",
-            err
-        );
-    }
+    // no new calls into our code from now on
+    disable_hooks();
 
-    if let Err(err) = unsafe { CVENGINESERVER_GMOD_SENDTOCLIENT.disable() } {
-        println!(
-            "[PackUwUs] Failed to disable CVEngineServer::Gmod_SendToClient: {}",
-            err
-        );
-    }
+    unsafe {
+        for (name, _) in LUA_FUNCTIONS {
+            lua.push_nil();
+            lua.set_field(LUA_GLOBALSINDEX, *name);
+        }
 
-    if let Err(err) = unsafe { CVENGINESERVER_GMOD_SENDTOCLIENTS.disable() } {
-        println!(
-            "[PackUwUs] Failed to disable CVEngineServer::Gmod_SendToClient (all clients): {}",
-            err
-        );
+        shutdown(lua);
+
+        *PACKUWUS.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        CLIENT_FILES_TABLE = None;
     }
 
-    // TODO: there's no module unload support, so these lines are useless i guess?
-    //                                            ~~~~~~~~~~~
-    //                                                 |
-    // \/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/\/
-    unsafe {
-        lua.push_nil();
-        lua.set_field(LUA_GLOBALSINDEX, b"PackUwUs_Pack\0".as_ptr() as _);
+    remove_panic_hook();
 
-        lua.push_nil();
-        lua.set_field(LUA_GLOBALSINDEX, b"PackUwUs_SetPackContent\0".as_ptr() as _);
-    }
+    println!("[PackUwUs] Unloaded");
 
     0
 }
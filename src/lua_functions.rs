@@ -1,145 +1,337 @@
 use std::{
-    sync::{Arc, Mutex},
-    thread,
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use gmod::{
-    lua::{LuaReference, State},
+    lua::{LuaReference, LuaString, State},
     lua_function, lua_string,
 };
 use lazy_static::lazy_static;
 
-use crate::PACKUWUS;
+use crate::{
+    compression::Codec,
+    crash::guard_detour,
+    hooks::Capability,
+    pack::{BuildError, Progress, ProgressState},
+    packuwus::{
+        BuiltPack, DrainError, ErrorCode, PackSnapshot, PackStats, PackUwUs, ReconcileReport,
+        TryServeError,
+    },
+    with_packuwus,
+};
+
+const THINK_HOOK_NAME: &str = "PackUwUs completions";
+
+/// Timers created by sv_startup.lua, they call module functions so they're removed with it
+const LUA_TIMERS: &[&str] = &["PackUwUs auto repack", "PackUwUs reconcile"];
+
+struct JobCallbacks {
+    done: LuaReference,
+    progress: Option<LuaReference>,
+}
 
-const LUA_SYNC_THREAD_TIMER_NAME: &str = "PackUwUs lua sync thread";
+struct PackJob {
+    id: usize,
+    started: Instant,
+    progress: Arc<Progress>,
+    reported: Option<ProgressState>,
+    callbacks: Vec<JobCallbacks>,
+    thread: JoinHandle<()>,
+}
+
+/// At most one pack is built at a time. Packing while one is running marks it stale,
+/// stale pack is cancelled and a single follow-up pack is started once it stops.
+#[derive(Default)]
+struct PackQueue {
+    current: Option<PackJob>,
+    stale: bool,
+    /// Callbacks waiting for the follow-up pack
+    follow_up: Vec<JobCallbacks>,
+    next_id: usize,
+}
+
+/// Finished build, pushed by pack worker and drained on game thread
+struct Completion {
+    job_id: usize,
+    result: Result<BuiltPack, TryServeError>,
+}
 
-#[derive(PartialEq)]
-enum ServeFileStatus {
-    Idle,
-    Working,
-    Failed((LuaReference, String)),
-    Done((LuaReference, String)),
+struct PackResult {
+    hash: String,
+    stats: PackStats,
+    reconcile: ReconcileReport,
+    duration: Duration,
 }
 
 lazy_static! {
-    static ref SERVE_FILE_STATUS: Arc<Mutex<ServeFileStatus>> =
-        Arc::new(Mutex::new(ServeFileStatus::Idle));
+    static ref PACK_QUEUE: Mutex<PackQueue> = Mutex::new(PackQueue::default());
+    static ref COMPLETIONS: Mutex<VecDeque<Completion>> = Mutex::new(VecDeque::new());
 }
 
-unsafe fn start_sync_thread(lua: State) {
-    println!("[PackUwUs] Starting lua sync thread...");
+static THINK_HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
 
-    lua.get_global(lua_string!("timer"));
+// like `with_packuwus`, queues must stay usable after a caught panic
 
-    if lua.is_table(-1) {
-        lua.get_field(-1, lua_string!("Create"));
+fn pack_queue() -> MutexGuard<'static, PackQueue> {
+    PACK_QUEUE.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-        if lua.is_function(-1) {
-            lua.push_string(LUA_SYNC_THREAD_TIMER_NAME); // name
-            lua.push_number(0.0); // interval
-            lua.push_number(0.0); // reps
-            lua.push_function(lua_sync_thread); // callback
+fn completions() -> MutexGuard<'static, VecDeque<Completion>> {
+    COMPLETIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-            if !lua.pcall_ignore(4, 0) {
-                lua.error(format!(
-                    "Failed to setup lua sync thread: error occured in _G.timer.Create!"
-                ));
-            }
-        } else {
-            lua.pop(); // pop _G.timer.Create
+/// Pushes `{code, message, path}` table, `path` is used if error itself doesn't know it
+pub(crate) unsafe fn push_error(lua: State, err: &dyn ErrorCode, path: Option<&str>) {
+    lua.create_table(0, 3);
 
-            lua.error(format!(
-                "Failed to setup lua sync thread: _G.timer.Create is not a function (got type {})!",
-                lua.get_type(-1)
-            ));
-        }
+    lua.push_string(err.code());
+    lua.set_field(-2, lua_string!("code"));
+
+    lua.push_string(err.to_string().as_str());
+    lua.set_field(-2, lua_string!("message"));
+
+    if let Some(path) = err.path().or(path) {
+        lua.push_string(path);
+        lua.set_field(-2, lua_string!("path"));
+    }
+}
+
+/// Errors that happen outside of PackUwUs calls, e.g. in detours, are passed to
+/// `_G.PackUwUs_OnError(err)` if it's defined
+pub(crate) unsafe fn report_error(lua: State, err: &dyn ErrorCode, path: Option<&str>) {
+    lua.get_global(lua_string!("PackUwUs_OnError"));
+
+    if !lua.is_function(-1) {
+        lua.pop();
+
+        return;
+    }
+
+    push_error(lua, err, path);
+
+    if !lua.pcall_ignore(1, 0) {
+        println!("[PackUwUs] PackUwUs_OnError errored!");
+    }
+}
+
+/// Calls `hook.<function>("Think", THINK_HOOK_NAME, ...)`, pushed arguments are passed after
+/// the name. Returns false if hook library isn't loaded yet.
+unsafe fn call_think_hook_function(lua: State, function: LuaString, nargs: i32) -> bool {
+    lua.get_global(lua_string!("hook"));
+
+    if !lua.is_table(-1) {
+        lua.pop_n(1 + nargs);
+
+        return false;
+    }
+
+    lua.get_field(-1, function);
+    lua.remove(-2); // remove _G.hook
+
+    if !lua.is_function(-1) {
+        lua.pop_n(1 + nargs);
+
+        return false;
+    }
+
+    // function is below arguments
+    lua.insert(-1 - nargs);
+
+    lua.push_string("Think");
+    lua.insert(-1 - nargs);
+    lua.push_string(THINK_HOOK_NAME);
+    lua.insert(-1 - nargs);
+
+    lua.pcall_ignore(2 + nargs, 0)
+}
+
+/// Completions are drained from a single Think hook which stays until module is unloaded.
+/// Until hook library is loaded they are drained whenever pack functions are called.
+unsafe fn install_think_hook(lua: State) {
+    if THINK_HOOK_INSTALLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    lua.push_function(drain_completions);
+
+    if call_think_hook_function(lua, lua_string!("Add"), 1) {
+        println!("[PackUwUs] Installed completions think hook");
+
+        THINK_HOOK_INSTALLED.store(true, Ordering::Relaxed);
     } else {
-        lua.error(format!(
-            "Failed to setup lua sync thread: _G.timer is not a table (got type {})!",
-            lua.get_type(-1)
-        ));
+        #[cfg(debug_assertions)]
+        println!("[PackUwUs] hook library isn't available yet, completions are drained manually");
     }
+}
 
-    lua.pop(); // pop _G.timer
+unsafe fn remove_think_hook(lua: State) {
+    if THINK_HOOK_INSTALLED.swap(false, Ordering::Relaxed)
+        && !call_think_hook_function(lua, lua_string!("Remove"), 0)
+    {
+        println!("[PackUwUs] Failed to remove completions think hook");
+    }
 }
 
-unsafe fn stop_sync_thread(lua: State) {
-    println!("[PackUwUs] Stopping lua sync thread...");
+/// Compares packed files with client files before packing and ingests missing ones if enabled.
+/// Ingested files go through `_G.PackUwUs_HandlePack`, so state must not be locked.
+unsafe fn reconcile(lua: State) {
+    let Some((mut report, ingest)) = with_packuwus(|packuwus| {
+        packuwus
+            .needs_reconcile()
+            .then(|| (packuwus.reconcile(), packuwus.options.ingest_missing))
+    }) else {
+        return;
+    };
 
-    lua.get_global(lua_string!("timer"));
+    if ingest {
+        report.unpacked.retain(|path| {
+            let content = match with_packuwus(|packuwus| packuwus.read_file(path)) {
+                Ok(content) => content,
+                Err(err) => {
+                    println!(
+                        "[PackUwUs] Failed to ingest {} ({}): {}",
+                        path,
+                        err.code(),
+                        err
+                    );
 
-    if lua.is_table(-1) {
-        lua.get_field(-1, lua_string!("Remove"));
+                    return true;
+                }
+            };
 
-        if lua.is_function(-1) {
-            lua.push_string(LUA_SYNC_THREAD_TIMER_NAME); // name
+            let new_content = match PackUwUs::handle_pack(lua, path, &content) {
+                Ok((true, new_content)) => new_content.unwrap_or(content),
+                Ok((false, _)) => return true,
+                Err(err) => {
+                    report_error(lua, &err, Some(path));
 
-            if !lua.pcall_ignore(1, 0) {
-                lua.error(format!(
-                    "Failed to stop lua sync thread: error occured in _G.timer.Remove!"
-                ));
+                    return true;
+                }
+            };
+
+            if let Err(err) = with_packuwus(|packuwus| packuwus.add_file(path, Some(new_content))) {
+                println!(
+                    "[PackUwUs] Failed to ingest {} ({}): {}",
+                    path,
+                    err.code(),
+                    err
+                );
+
+                return true;
             }
-        } else {
-            lua.pop(); // pop _G.timer.Create
 
-            lua.error(format!(
-                "Failed to stop lua sync thread: _G.timer.Remove is not a function (got type {})!",
-                lua.get_type(-1)
-            ));
-        }
-    } else {
-        lua.error(format!(
-            "Failed to stop lua sync thread: _G.timer is not a table (got type {})!",
-            lua.get_type(-1)
-        ));
+            report.ingested.push(path.clone());
+
+            false
+        });
+    }
+
+    if !report.unpacked.is_empty() || !report.orphaned.is_empty() || !report.ingested.is_empty() {
+        println!(
+            "[PackUwUs] Reconciled: {} client files aren't packed, {} packed files aren't sent to clients, {} ingested",
+            report.unpacked.len(),
+            report.orphaned.len(),
+            report.ingested.len()
+        );
     }
 
-    lua.pop(); // pop _G.timer
+    with_packuwus(|packuwus| packuwus.reconcile_report = report);
 }
 
 #[lua_function]
 pub(crate) unsafe fn pack_sync(lua: State) -> i32 {
-    match PACKUWUS.as_mut().unwrap().try_serve() {
+    drain(lua);
+
+    let result = if pack_queue().current.is_some() {
+        Err(TryServeError::AsyncPackRunning)
+    } else {
+        reconcile(lua);
+
+        with_packuwus(|packuwus| packuwus.try_serve())
+    };
+
+    match result {
         Ok(hash) => {
-            if let Some(hash) = hash {
-                lua.push_string(hash.as_str());
+            lua.push_string(hash.as_str());
 
-                return 1;
-            }
+            1
         }
-        Err(err) => lua.error(format!("Failed to pack: {}", err)),
+        Err(err) => {
+            lua.push_boolean(false);
+            push_error(lua, &err, None);
+
+            2
+        }
+    }
+}
+
+unsafe fn reference_callbacks(lua: State) -> JobCallbacks {
+    let progress = if lua.is_none_or_nil(2) {
+        None
+    } else {
+        lua.push_value(2);
+
+        Some(lua.reference())
     };
 
-    lua.push_boolean(false);
+    lua.push_value(1);
 
-    1
+    JobCallbacks {
+        done: lua.reference(),
+        progress,
+    }
+}
+
+unsafe fn dereference_callbacks(lua: State, callbacks: &JobCallbacks) {
+    lua.dereference(callbacks.done);
+
+    if let Some(progress) = callbacks.progress {
+        lua.dereference(progress);
+    }
 }
 
+fn start_job(queue: &mut PackQueue, snapshot: PackSnapshot, callbacks: Vec<JobCallbacks>) {
+    let id = queue.next_id;
+    let progress = Arc::new(Progress::default());
+    let worker_progress = progress.clone();
+
+    queue.next_id += 1;
+    queue.current = Some(PackJob {
+        id,
+        started: Instant::now(),
+        progress,
+        reported: None,
+        callbacks,
+        thread: thread::spawn(move || {
+            let result = snapshot.build(&worker_progress);
+
+            completions().push_back(Completion { job_id: id, result });
+        }),
+    });
+}
+
+/// `PackUwUs_PackAsync(callback, progress)`. Returns `true` if a pack was started or queued.
+/// `callback(err, result)` is called once the pack is published, `progress(state)` while it's built.
 #[lua_function]
 pub(crate) unsafe fn pack_async(lua: State) -> i32 {
-    match SERVE_FILE_STATUS.try_lock() {
-        Ok(ref mut status) => match **status {
-            ServeFileStatus::Working => {
-                lua.push_boolean(false);
-
-                return 1;
-            }
-            ServeFileStatus::Failed((callback_ref, _)) => {
-                lua.dereference(callback_ref);
-            }
-            ServeFileStatus::Done((callback_ref, _)) => {
-                lua.dereference(callback_ref);
-            }
-            _ => (),
-        },
-        Err(_) => {
-            lua.push_boolean(false);
+    lua.check_function(1);
 
-            return 1;
-        }
+    if !lua.is_none_or_nil(2) {
+        lua.check_function(2);
     }
 
-    if !PACKUWUS.as_ref().unwrap().content_changed {
+    drain(lua);
+    install_think_hook(lua);
+    reconcile(lua);
+
+    if !with_packuwus(|packuwus| packuwus.content_changed) {
         // nothing to repack
 
         lua.push_boolean(false);
@@ -147,94 +339,546 @@ pub(crate) unsafe fn pack_async(lua: State) -> i32 {
         return 1;
     }
 
-    lua.check_function(1);
+    let mut queue = pack_queue();
 
-    start_sync_thread(lua);
+    if let Some(job) = queue.current.as_ref() {
+        // files were changed after running pack took its snapshot
+        job.progress.cancel();
 
-    let callback_ref = lua.reference();
+        queue.stale = true;
+        queue.follow_up.push(reference_callbacks(lua));
 
-    *SERVE_FILE_STATUS.lock().unwrap() = ServeFileStatus::Working;
+        lua.push_boolean(true);
 
-    thread::spawn(move || match PACKUWUS.as_mut().unwrap().try_serve() {
-        Ok(packed_hash) => {
-            if let Some(packed_hash) = packed_hash {
-                *SERVE_FILE_STATUS.lock().unwrap() =
-                    ServeFileStatus::Done((callback_ref, packed_hash));
+        return 1;
+    }
 
-                return;
-            }
-        }
-        Err(err) => {
-            *SERVE_FILE_STATUS.lock().unwrap() =
-                ServeFileStatus::Failed((callback_ref, err.to_string()));
-        }
-    });
+    let Some(snapshot) = with_packuwus(|packuwus| packuwus.snapshot()) else {
+        lua.push_boolean(false);
+
+        return 1;
+    };
+
+    start_job(&mut queue, snapshot, vec![reference_callbacks(lua)]);
 
     lua.push_boolean(true);
 
     1
 }
 
+/// Cancels running pack and its follow-up. Their callbacks get an error, nothing is published.
 #[lua_function]
-unsafe fn lua_sync_thread(lua: State) -> i32 {
-    #[cfg(debug_assertions)]
-    println!("lua_sync_thread");
+pub(crate) unsafe fn cancel_pack(lua: State) -> i32 {
+    let mut queue = pack_queue();
+    let follow_up = mem::take(&mut queue.follow_up);
 
-    match SERVE_FILE_STATUS.try_lock() {
-        Ok(ref mut status) => match **status {
-            ServeFileStatus::Failed((callback_ref, ref err)) => {
-                println!("[PackUwUs] Serve file failed: {}", err);
+    queue.stale = false;
 
-                lua.from_reference(callback_ref);
+    let cancelled = match queue.current.as_mut() {
+        Some(job) => {
+            job.progress.cancel();
+            job.callbacks.extend(follow_up);
 
-                lua.push_string(err.as_str());
-                lua.push_nil();
+            true
+        }
+        None => false,
+    };
 
-                if !lua.pcall_ignore(2, 0) {
-                    println!(
-                        "[PackUwUs] Error in lua sync thread: PackUwUs_Pack callback errored!"
-                    );
-                }
+    drop(queue);
+
+    if cancelled {
+        println!("[PackUwUs] Cancelling pack");
+    }
+
+    lua.push_boolean(cancelled);
+
+    1
+}
+
+unsafe fn report_progress(lua: State, callbacks: &[LuaReference], state: ProgressState) {
+    for callback in callbacks {
+        lua.from_reference(*callback);
+
+        lua.create_table(0, 3);
+
+        lua.push_integer(state.files as _);
+        lua.set_field(-2, lua_string!("files"));
+
+        lua.push_integer(state.total as _);
+        lua.set_field(-2, lua_string!("total"));
+
+        lua.push_integer(state.bytes as _);
+        lua.set_field(-2, lua_string!("bytes"));
+
+        if !lua.pcall_ignore(1, 0) {
+            println!("[PackUwUs] Pack progress callback errored!");
+        }
+    }
+}
+
+unsafe fn push_pack_result(lua: State, result: &PackResult) {
+    lua.create_table(0, 9);
+
+    lua.push_string(result.hash.as_str());
+    lua.set_field(-2, lua_string!("hash"));
+
+    lua.push_integer(result.stats.files as _);
+    lua.set_field(-2, lua_string!("files"));
+
+    lua.push_integer(result.stats.chunks as _);
+    lua.set_field(-2, lua_string!("chunks"));
+
+    lua.push_integer(result.stats.bytes as _);
+    lua.set_field(-2, lua_string!("bytes"));
+
+    lua.push_boolean(result.stats.delta);
+    lua.set_field(-2, lua_string!("delta"));
+
+    lua.push_integer(result.reconcile.unpacked.len() as _);
+    lua.set_field(-2, lua_string!("unpacked"));
+
+    lua.push_integer(result.reconcile.orphaned.len() as _);
+    lua.set_field(-2, lua_string!("orphaned"));
+
+    lua.push_integer(result.reconcile.ingested.len() as _);
+    lua.set_field(-2, lua_string!("ingested"));
+
+    lua.push_number(result.duration.as_secs_f64());
+    lua.set_field(-2, lua_string!("seconds"));
+}
+
+/// Handles finished build of current job. Returns callbacks to call, unless job was stale
+/// and follow-up was started instead.
+fn finish_job(
+    queue: &mut PackQueue,
+    completion: Completion,
+) -> Option<(Vec<JobCallbacks>, Result<PackResult, TryServeError>)> {
+    if queue.current.as_ref().map(|job| job.id) != Some(completion.job_id) {
+        return None;
+    }
+
+    let job = queue.current.take()?;
+
+    if job.thread.join().is_err() {
+        println!("[PackUwUs] Pack thread panicked");
+    }
+
+    if queue.stale {
+        println!("[PackUwUs] Pack is stale, starting follow-up pack");
+
+        // stale result is thrown away, its files are part of follow-up snapshot
+        let snapshot = with_packuwus(|packuwus| {
+            packuwus.pack_failed();
+            packuwus.snapshot()
+        });
+
+        queue.stale = false;
+
+        let mut callbacks = job.callbacks;
+
+        callbacks.append(&mut queue.follow_up);
+
+        let Some(snapshot) = snapshot else {
+            return Some((callbacks, Err(TryServeError::NothingToPack)));
+        };
+
+        start_job(queue, snapshot, callbacks);
+
+        return None;
+    }
+
+    let result = match completion.result {
+        // pack could finish right before it was cancelled
+        Ok(_) if job.progress.is_cancelled() => {
+            Err(TryServeError::BuildFailed(BuildError::Cancelled))
+        }
+        result => result,
+    }
+    .and_then(|built| {
+        let stats = built.stats();
+
+        with_packuwus(|packuwus| {
+            packuwus.publish(built).map(|hash| PackResult {
+                hash,
+                stats,
+                reconcile: packuwus.reconcile_report.clone(),
+                duration: job.started.elapsed(),
+            })
+        })
+    });
 
-                lua.dereference(callback_ref);
+    if result.is_err() {
+        with_packuwus(|packuwus| packuwus.pack_failed());
+    }
+
+    Some((job.callbacks, result))
+}
 
-                **status = ServeFileStatus::Idle;
+/// Reports progress and delivers finished packs. Panics must not unwind into Lua, they're
+/// reported to `PackUwUs_OnError` instead.
+unsafe fn drain(lua: State) {
+    if guard_detour("PackUwUs drain", String::new, || drain_completed(lua)).is_none() {
+        report_error(lua, &DrainError::Panicked, None);
+    }
+}
 
-                stop_sync_thread(lua);
+/// Lock is never held while calling into Lua, so callbacks may start next pack right away
+unsafe fn drain_completed(lua: State) {
+    let progress = {
+        let mut queue = pack_queue();
+
+        queue.current.as_mut().and_then(|job| {
+            let state = job.progress.state();
+
+            if job.reported == Some(state) {
+                return None;
             }
-            ServeFileStatus::Done((callback_ref, ref hash)) => {
-                println!("[PackUwUs] Serve file done! Hash: {}", hash);
 
-                lua.from_reference(callback_ref);
+            job.reported = Some(state);
 
-                lua.push_nil();
-                lua.push_string(hash.as_str());
+            Some((
+                job.callbacks
+                    .iter()
+                    .filter_map(|callbacks| callbacks.progress)
+                    .collect::<Vec<_>>(),
+                state,
+            ))
+        })
+    };
 
-                if !lua.pcall_ignore(2, 0) {
-                    println!(
-                        "[PackUwUs] Error in lua sync thread: PackUwUs_Pack callback errored!"
-                    );
-                }
+    if let Some((callbacks, state)) = progress {
+        report_progress(lua, &callbacks, state);
+    }
+
+    loop {
+        let Some(completion) = completions().pop_front() else {
+            break;
+        };
+
+        let Some((callbacks, result)) = finish_job(&mut pack_queue(), completion) else {
+            continue;
+        };
+
+        match result {
+            Ok(ref result) => println!(
+                "[PackUwUs] Pack done in {:.2} seconds! Hash: {}",
+                result.duration.as_secs_f64(),
+                result.hash
+            ),
+            Err(ref err) => println!("[PackUwUs] Pack failed: {}", err),
+        }
 
-                lua.dereference(callback_ref);
+        for callbacks in callbacks.iter() {
+            lua.from_reference(callbacks.done);
 
-                **status = ServeFileStatus::Idle;
+            match result {
+                Ok(ref result) => {
+                    lua.push_nil();
+                    push_pack_result(lua, result);
+                }
+                Err(ref err) => {
+                    push_error(lua, err, None);
+                    lua.push_nil();
+                }
+            }
 
-                stop_sync_thread(lua);
+            if !lua.pcall_ignore(2, 0) {
+                println!("[PackUwUs] PackUwUs_PackAsync callback errored!");
             }
-            _ => (),
-        },
-        Err(_) => (),
+
+            dereference_callbacks(lua, callbacks);
+        }
     }
+}
+
+#[lua_function]
+unsafe fn drain_completions(lua: State) -> i32 {
+    drain(lua);
 
     0
 }
 
+unsafe fn remove_lua_timers(lua: State) {
+    lua.get_global(lua_string!("timer"));
+
+    if !lua.is_table(-1) {
+        lua.pop();
+
+        return;
+    }
+
+    for name in LUA_TIMERS {
+        lua.get_field(-1, lua_string!("Remove"));
+        lua.push_string(name);
+
+        if !lua.pcall_ignore(1, 0) {
+            println!("[PackUwUs] Failed to remove {} timer", name);
+        }
+    }
+
+    lua.pop();
+}
+
+/// Cancels running pack and releases everything async packing holds,
+/// so module can be unloaded
+pub(crate) unsafe fn shutdown(lua: State) {
+    remove_think_hook(lua);
+    remove_lua_timers(lua);
+
+    let mut queue = pack_queue();
+    let follow_up = mem::take(&mut queue.follow_up);
+    let job = queue.current.take();
+
+    queue.stale = false;
+
+    drop(queue);
+
+    if let Some(job) = job {
+        println!("[PackUwUs] Waiting for pack thread to finish...");
+
+        job.progress.cancel();
+
+        if job.thread.join().is_err() {
+            println!("[PackUwUs] Pack thread panicked");
+        }
+
+        for callbacks in job.callbacks.iter().chain(follow_up.iter()) {
+            dereference_callbacks(lua, callbacks);
+        }
+    }
+
+    completions().clear();
+}
+
 #[lua_function]
 pub(crate) unsafe fn set_pack_content(lua: State) -> i32 {
     println!("[PackUwUs] Setting pack content");
 
-    PACKUWUS.as_mut().unwrap().packed_contents = Some(lua.check_string(1).to_string());
+    let packed_contents = lua.check_binary_string(1).to_vec();
+
+    with_packuwus(|packuwus| packuwus.packed_contents = Some(packed_contents));
 
     0
 }
+
+#[lua_function]
+pub(crate) unsafe fn set_option(lua: State) -> i32 {
+    let name = lua.check_string(1).to_string();
+    // edited outside of the lock, lua.error must not leave the state locked
+    let mut options = with_packuwus(|packuwus| packuwus.options);
+
+    match name.as_str() {
+        "delta" => options.delta = lua.check_boolean(2),
+        "codec" => {
+            let level = if lua.is_none_or_nil(3) {
+                9
+            } else {
+                lua.check_integer(3) as i32
+            };
+
+            match Codec::parse(&lua.check_string(2), level) {
+                Ok(codec) => options.compression.codec = codec,
+                Err(err) => lua.error(format!("Failed to set codec: {}", err)),
+            }
+        }
+        "compress_threshold" => options.compression.min_size = lua.check_integer(2).max(0) as _,
+        "solid" => options.compression.solid = lua.check_boolean(2),
+        "dictionary" => options.compression.dictionary = lua.check_boolean(2),
+        "ingest_missing" => options.ingest_missing = lua.check_boolean(2),
+        _ => lua.error(format!("Unknown option \"{}\"", name)),
+    }
+
+    with_packuwus(|packuwus| packuwus.set_options(options));
+
+    0
+}
+
+#[lua_function]
+pub(crate) unsafe fn benchmark_layouts(lua: State) -> i32 {
+    let results = match with_packuwus(|packuwus| packuwus.benchmark_layouts()) {
+        Ok(results) => results,
+        Err(err) => lua.error(format!("Failed to benchmark: {}", err)),
+    };
+
+    lua.create_table(results.len() as _, 0);
+
+    for (i, result) in results.iter().enumerate() {
+        lua.create_table(0, 4);
+
+        lua.push_boolean(result.solid);
+        lua.set_field(-2, lua_string!("solid"));
+
+        lua.push_integer(result.bytes as _);
+        lua.set_field(-2, lua_string!("bytes"));
+
+        lua.push_integer(result.chunks as _);
+        lua.set_field(-2, lua_string!("chunks"));
+
+        lua.push_number(result.duration.as_secs_f64());
+        lua.set_field(-2, lua_string!("seconds"));
+
+        lua.raw_seti(-2, (i + 1) as _);
+    }
+
+    1
+}
+
+#[lua_function]
+pub(crate) unsafe fn capabilities(lua: State) -> i32 {
+    lua.create_table(0, Capability::ALL.len() as _);
+
+    for capability in Capability::ALL {
+        lua.push_string(capability.name());
+        lua.push_boolean(capability.is_supported());
+        lua.set_table(-3);
+    }
+
+    1
+}
+
+unsafe fn push_paths(lua: State, paths: &[String]) {
+    lua.create_table(paths.len() as _, 0);
+
+    for (i, path) in paths.iter().enumerate() {
+        lua.push_string(path.as_str());
+        lua.raw_seti(-2, (i + 1) as _);
+    }
+}
+
+#[lua_function]
+pub(crate) unsafe fn list_files(lua: State) -> i32 {
+    push_paths(lua, &with_packuwus(|packuwus| packuwus.file_paths()));
+
+    1
+}
+
+#[lua_function]
+pub(crate) unsafe fn get_file(lua: State) -> i32 {
+    let path = lua.check_string(1).to_string();
+    let content = with_packuwus(|packuwus| packuwus.file(&path).map(|file| file.content.clone()));
+
+    match content {
+        Some(content) => lua.push_binary_string(&content),
+        None => lua.push_nil(),
+    }
+
+    1
+}
+
+#[lua_function]
+pub(crate) unsafe fn is_packed(lua: State) -> i32 {
+    let path = lua.check_string(1).to_string();
+
+    lua.push_boolean(with_packuwus(|packuwus| packuwus.is_packed(&path)));
+
+    1
+}
+
+#[lua_function]
+pub(crate) unsafe fn add_virtual_file(lua: State) -> i32 {
+    let path = lua.check_string(1).to_string();
+    let content = lua.check_binary_string(2).to_vec();
+
+    with_packuwus(|packuwus| packuwus.add_virtual_file(&path, content));
+
+    0
+}
+
+#[lua_function]
+pub(crate) unsafe fn remove_file(lua: State) -> i32 {
+    let path = lua.check_string(1).to_string();
+
+    match with_packuwus(|packuwus| packuwus.remove_file(&path)) {
+        Ok(()) => {
+            lua.push_boolean(true);
+
+            1
+        }
+        Err(err) => {
+            lua.push_boolean(false);
+            push_error(lua, &err, Some(&path));
+
+            2
+        }
+    }
+}
+
+/// `PackUwUs_RetrainDictionary()`, next dictionary encoded pack trains a new dictionary
+#[lua_function]
+pub(crate) unsafe fn retrain_dictionary(_lua: State) -> i32 {
+    with_packuwus(|packuwus| packuwus.retrain_dictionary());
+
+    0
+}
+
+#[lua_function]
+pub(crate) unsafe fn remove_missing_files(lua: State) -> i32 {
+    push_paths(
+        lua,
+        &with_packuwus(|packuwus| packuwus.remove_missing_files()),
+    );
+
+    1
+}
+
+/// `PackUwUs_ReconcileReport()`, returns `{unpacked, orphaned, ingested}` path lists
+/// of the last reconcile, which runs before every pack
+#[lua_function]
+pub(crate) unsafe fn reconcile_report(lua: State) -> i32 {
+    let report = with_packuwus(|packuwus| packuwus.reconcile_report.clone());
+
+    lua.create_table(0, 3);
+
+    push_paths(lua, &report.unpacked);
+    lua.set_field(-2, lua_string!("unpacked"));
+
+    push_paths(lua, &report.orphaned);
+    lua.set_field(-2, lua_string!("orphaned"));
+
+    push_paths(lua, &report.ingested);
+    lua.set_field(-2, lua_string!("ingested"));
+
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_survives_panic_while_locked() {
+        let _ = thread::spawn(|| {
+            let _queue = pack_queue();
+
+            panic!("panicked with pack queue locked");
+        })
+        .join();
+
+        assert!(PACK_QUEUE.is_poisoned());
+        assert!(pack_queue().current.is_none());
+        assert!(completions().is_empty());
+    }
+
+    #[test]
+    fn ignores_completion_of_other_job() {
+        let mut queue = PackQueue::default();
+        let completion = |job_id| Completion {
+            job_id,
+            result: Err(TryServeError::NothingToPack),
+        };
+
+        assert!(finish_job(&mut queue, completion(0)).is_none());
+
+        queue.current = Some(PackJob {
+            id: 1,
+            started: Instant::now(),
+            progress: Arc::new(Progress::default()),
+            reported: None,
+            callbacks: vec![],
+            thread: thread::spawn(|| {}),
+        });
+
+        assert!(finish_job(&mut queue, completion(0)).is_none());
+        assert!(queue.current.is_some());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     ffi::{c_char, c_int, c_void, CString},
     fmt::Debug,
     fs::File,
@@ -8,16 +9,35 @@ use std::{
     ptr::null_mut,
 };
 
-use goblin::elf::Elf;
+use goblin::elf::{
+    program_header::PT_LOAD,
+    sym::{STT_FUNC, STT_OBJECT},
+    Elf,
+};
 use procfs::{
-    process::{MemoryMap, Process},
+    process::{MMPermissions, MemoryMap, Process},
     ProcError,
 };
 
+use crate::signature::{Pattern, PatternError, Signature};
+
+const MAX_CANDIDATES: usize = 10;
+const PAGE_SIZE: u64 = 0x1000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ModuleFromMemMapError {
     #[error("Memory map not found")]
     NotFound,
+    #[error("Failed to open file: {0}")]
+    OpenFile(std::io::Error),
+    #[error("Failed to read file: {0}")]
+    ReadFile(std::io::Error),
+    #[error("Failed to parse file's ELF header: {0}")]
+    ParseFile(goblin::error::Error),
+    #[error("ELF has no loadable segments")]
+    NoLoadSegment,
+    #[error("First loadable segment is not mapped")]
+    LoadSegmentNotMapped,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -32,14 +52,59 @@ pub enum ModuleFromProcessError {
 
 #[derive(thiserror::Error, Debug)]
 pub enum SymbolError {
-    #[error("Failed to open file: {0}")]
-    OpenFile(std::io::Error),
-    #[error("Failed to read file: {0}")]
-    ReadFile(std::io::Error),
-    #[error("Failed to parse file's ELF header: {0}")]
-    ParseFile(goblin::error::Error),
-    #[error("Symbol not found")]
+    #[error("Symbol not found{}", format_candidates(.0))]
+    NotFound(Vec<String>),
+    #[error("Symbol name is ambiguous{}", format_candidates(.0))]
+    Ambiguous(Vec<String>),
+}
+
+fn format_candidates(candidates: &[String]) -> String {
+    if candidates.is_empty() {
+        String::new()
+    } else {
+        format!(". Candidates: {}", candidates.join(", "))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureError {
+    #[error("Invalid pattern: {0}")]
+    Pattern(PatternError),
+    #[error("Signature not found")]
     NotFound,
+    #[error("Failed to resolve relative address")]
+    ResolveFailed,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{symbol}{}", format_signature_errors(.signatures))]
+pub struct ResolveError {
+    pub symbol: SymbolError,
+    pub signatures: Vec<SignatureError>,
+}
+
+fn format_signature_errors(errors: &[SignatureError]) -> String {
+    errors
+        .iter()
+        .enumerate()
+        .map(|(i, err)| format!("; signature #{}: {}", i, err))
+        .collect()
+}
+
+/// Which method found the address
+#[derive(Debug, Clone, Copy)]
+pub enum Resolution {
+    Symbol,
+    Signature(usize),
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resolution::Symbol => write!(f, "symbol"),
+            Resolution::Signature(index) => write!(f, "signature #{}", index),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -50,22 +115,164 @@ pub enum InterfaceError {
     UnexpectedNulInName,
 }
 
+/// Demangles only qualified name of Itanium C++ ABI symbol, without arguments:
+/// `_ZN12GModDataPack15AddOrUpdateFileEP7LuaFileb` -> `GModDataPack::AddOrUpdateFile`
+fn demangle_name(mangled: &str) -> Option<String> {
+    let mut rest = mangled.strip_prefix("_Z")?;
+
+    let nested = if let Some(nested_rest) = rest.strip_prefix('N') {
+        rest = nested_rest.trim_start_matches(['r', 'V', 'K']);
+
+        true
+    } else {
+        false
+    };
+
+    let mut parts = vec![];
+
+    loop {
+        let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+
+        if digits == 0 {
+            break;
+        }
+
+        let len: usize = rest[..digits].parse().ok()?;
+
+        parts.push(rest.get(digits..digits + len)?);
+        rest = &rest[digits + len..];
+
+        if !nested {
+            break;
+        }
+    }
+
+    if parts.is_empty() || (nested && !rest.starts_with('E')) {
+        return None;
+    }
+
+    Some(parts.join("::"))
+}
+
+#[derive(Debug)]
+struct Symbol {
+    demangled: Option<String>,
+    value: u64,
+}
+
+/// Mapping of module's file with its runtime permissions
+#[derive(Debug)]
+pub struct Segment {
+    pub start: u64,
+    pub size: u64,
+    pub perms: MMPermissions,
+}
+
+impl Segment {
+    pub fn is_executable(&self) -> bool {
+        self.perms
+            .contains(MMPermissions::READ | MMPermissions::EXECUTE)
+    }
+}
+
 pub(crate) struct Module {
     pub path: PathBuf,
-    pub start_address: u64,
-    pub size: u64,
+    /// Difference between ELF virtual addresses and where they're mapped in process
+    pub load_bias: u64,
+    pub segments: Vec<Segment>,
+    symbols: HashMap<String, Symbol>,
 }
 
 #[allow(dead_code)]
 impl Module {
-    pub fn from_mem_map(mem_map: &MemoryMap) -> Result<Module, ModuleFromMemMapError> {
-        match &mem_map.pathname {
-            procfs::process::MMapPath::Path(path) => Ok(Module {
-                path: path.as_path().to_owned(),
-                start_address: mem_map.address.0,
-                size: mem_map.address.1 - mem_map.address.0,
-            }),
-            _ => Err(ModuleFromMemMapError::NotFound),
+    /// All maps must belong to the same file
+    pub fn from_mem_maps(mem_maps: &[MemoryMap]) -> Result<Module, ModuleFromMemMapError> {
+        let path = match mem_maps.first().map(|map| &map.pathname) {
+            Some(procfs::process::MMapPath::Path(path)) => path,
+            _ => return Err(ModuleFromMemMapError::NotFound),
+        };
+
+        let mut file = File::open(path).or_else(|err| Err(ModuleFromMemMapError::OpenFile(err)))?;
+
+        let mut buf = vec![];
+
+        file.read_to_end(&mut buf)
+            .or_else(|err| Err(ModuleFromMemMapError::ReadFile(err)))?;
+
+        let elf = Elf::parse(&buf).or_else(|err| Err(ModuleFromMemMapError::ParseFile(err)))?;
+
+        Ok(Module {
+            path: path.as_path().to_owned(),
+            load_bias: Module::load_bias(&elf, mem_maps)?,
+            segments: mem_maps
+                .iter()
+                .map(|map| Segment {
+                    start: map.address.0,
+                    size: map.address.1 - map.address.0,
+                    perms: map.perms,
+                })
+                .collect(),
+            symbols: Module::collect_symbols(&elf),
+        })
+    }
+
+    // first PT_LOAD segment is mapped from page containing its file offset, so
+    // its page aligned p_vaddr lands at start of that mapping
+    fn load_bias(elf: &Elf, mem_maps: &[MemoryMap]) -> Result<u64, ModuleFromMemMapError> {
+        let first_load = elf
+            .program_headers
+            .iter()
+            .find(|header| header.p_type == PT_LOAD)
+            .ok_or_else(|| ModuleFromMemMapError::NoLoadSegment)?;
+
+        let page_mask = !(PAGE_SIZE - 1);
+
+        mem_maps
+            .iter()
+            .filter(|map| map.offset == first_load.p_offset & page_mask)
+            .map(|map| map.address.0)
+            .min()
+            .map(|start| start.wrapping_sub(first_load.p_vaddr & page_mask))
+            .ok_or_else(|| ModuleFromMemMapError::LoadSegmentNotMapped)
+    }
+
+    // .symtab is gone in stripped binaries, but exported symbols are still in .dynsym
+    fn collect_symbols(elf: &Elf) -> HashMap<String, Symbol> {
+        let mut symbols = HashMap::new();
+
+        for (syms, strtab) in [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)] {
+            for sym in syms.iter() {
+                // imports are undefined and have no address in this module
+                if sym.is_import() || !matches!(sym.st_type(), STT_FUNC | STT_OBJECT) {
+                    continue;
+                }
+
+                if let Some(name) = strtab.get_at(sym.st_name) {
+                    symbols.entry(name.to_string()).or_insert_with(|| Symbol {
+                        demangled: demangle_name(name),
+                        value: sym.st_value,
+                    });
+                }
+            }
+        }
+
+        symbols
+    }
+
+    fn is_module_file(map: &MemoryMap, filename: &str) -> bool {
+        match &map.pathname {
+            procfs::process::MMapPath::Path(map_path) => {
+                match map_path.as_path().components().last() {
+                    Some(map_last_component) => match map_last_component {
+                        Component::Normal(map_filename) => {
+                            map_filename.to_str().unwrap_or("") == filename
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                }
+            }
+            _ => false,
         }
     }
 
@@ -73,58 +280,151 @@ impl Module {
         process: &Process,
         filename: &str,
     ) -> Result<Module, ModuleFromProcessError> {
-        if let Some(map) = process
+        let maps = process
             .maps()
-            .or_else(|err| Err(ModuleFromProcessError::Maps(err)))?
-            .into_iter()
-            .find(|map| match &map.pathname {
-                procfs::process::MMapPath::Path(map_path) => {
-                    match map_path.as_path().components().last() {
-                        Some(map_last_component) => match map_last_component {
-                            Component::Normal(map_filename) => {
-                                return map_filename.to_str().unwrap_or("") == filename
-                            }
-                            _ => false,
-                        },
-                        _ => false,
-                    }
-                }
-                _ => false,
-            })
-        {
-            Module::from_mem_map(&map).or_else(|err| Err(ModuleFromProcessError::MemMap(err)))
+            .or_else(|err| Err(ModuleFromProcessError::Maps(err)))?;
+
+        if let Some(module_maps) = Module::module_maps(&maps.0, filename) {
+            Module::from_mem_maps(&module_maps)
+                .or_else(|err| Err(ModuleFromProcessError::MemMap(err)))
         } else {
             Err(ModuleFromProcessError::NotFound)
         }
     }
 
-    unsafe fn memory_slice(&self) -> &[u8] {
-        std::slice::from_raw_parts(self.start_address as *const u8, self.size as usize)
+    /// Every mapping of the first file named `filename`
+    fn module_maps(maps: &[MemoryMap], filename: &str) -> Option<Vec<MemoryMap>> {
+        let first_map = maps
+            .iter()
+            .find(|map| Module::is_module_file(map, filename))?;
+
+        // same file may be mapped several times: code, read only data, writable data
+        Some(
+            maps.iter()
+                .filter(|map| map.pathname == first_map.pathname && map.inode == first_map.inode)
+                .cloned()
+                .collect(),
+        )
     }
 
-    pub fn symbol(&self, name: &str) -> Result<*const c_void, SymbolError> {
-        let mut file = File::open(&self.path).or_else(|err| Err(SymbolError::OpenFile(err)))?;
+    unsafe fn segment_slice(&self, segment: &Segment) -> &[u8] {
+        std::slice::from_raw_parts(segment.start as *const u8, segment.size as usize)
+    }
 
-        let mut buf = vec![];
+    /// Scans only executable segments, data can't contain code we're looking for
+    pub fn find_signature(&self, signature: &Signature) -> Result<*const c_void, SignatureError> {
+        let pattern =
+            Pattern::parse(&signature.pattern).or_else(|err| Err(SignatureError::Pattern(err)))?;
 
-        file.read_to_end(&mut buf)
-            .or_else(|err| Err(SymbolError::ReadFile(err)))?;
+        for segment in self
+            .segments
+            .iter()
+            .filter(|segment| segment.is_executable())
+        {
+            let data = unsafe { self.segment_slice(segment) };
 
-        let elf = Elf::parse(&buf).or_else(|err| Err(SymbolError::ParseFile(err)))?;
+            if let Some(offset) = pattern.find(data) {
+                return Ok(signature
+                    .resolve
+                    .apply(segment.start + offset as u64, &data[offset..])
+                    .ok_or_else(|| SignatureError::ResolveFailed)?
+                    as *const c_void);
+            }
+        }
 
-        Ok(elf
-            .syms
-            .iter()
-            .find_map(|sym| {
-                if let Some(sym_name) = elf.strtab.get_at(sym.st_name) {
-                    if sym_name == name {
-                        return Some(self.start_address + sym.st_value);
-                    }
-                }
+        Err(SignatureError::NotFound)
+    }
+
+    /// Looks up symbol, then falls back to signatures in order
+    pub fn resolve(
+        &self,
+        symbol: &str,
+        signatures: &[Signature],
+    ) -> Result<(*const c_void, Resolution), ResolveError> {
+        let symbol_err = match self.symbol(symbol) {
+            Ok(address) => return Ok((address, Resolution::Symbol)),
+            Err(err) => err,
+        };
+
+        let mut signature_errs = vec![];
 
-                None
+        for (i, signature) in signatures.iter().enumerate() {
+            match self.find_signature(signature) {
+                Ok(address) => return Ok((address, Resolution::Signature(i))),
+                Err(err) => signature_errs.push(err),
+            }
+        }
+
+        Err(ResolveError {
+            symbol: symbol_err,
+            signatures: signature_errs,
+        })
+    }
+
+    pub fn symbols_count(&self) -> usize {
+        self.symbols.len()
+    }
+
+    fn address(&self, symbol: &Symbol) -> *const c_void {
+        self.load_bias.wrapping_add(symbol.value) as *const c_void
+    }
+
+    /// Symbols whose name contains last part of requested name, e.g. for
+    /// `GarrysMod::AutoRefresh::HandleChange_Lua` every `HandleChange_Lua` overload
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        let demangled = demangle_name(name);
+        let needle = demangled
+            .as_deref()
+            .unwrap_or(name)
+            .rsplit("::")
+            .next()
+            .unwrap_or(name);
+
+        let mut candidates: Vec<String> = self
+            .symbols
+            .iter()
+            .filter(|(mangled, symbol)| {
+                symbol
+                    .demangled
+                    .as_deref()
+                    .unwrap_or(mangled)
+                    .contains(needle)
             })
-            .ok_or_else(|| SymbolError::NotFound)? as *const c_void)
+            .map(|(mangled, _)| mangled.clone())
+            .collect();
+
+        candidates.sort();
+        candidates.truncate(MAX_CANDIDATES);
+
+        candidates
+    }
+
+    pub fn symbol(&self, name: &str) -> Result<*const c_void, SymbolError> {
+        match self.symbols.get(name) {
+            Some(symbol) => Ok(self.address(symbol)),
+            None => Err(SymbolError::NotFound(self.candidates(name))),
+        }
+    }
+
+    /// Finds symbol by demangled qualified name, e.g. `GModDataPack::AddOrUpdateFile`
+    pub fn symbol_demangled(&self, name: &str) -> Result<*const c_void, SymbolError> {
+        let mut found = self
+            .symbols
+            .iter()
+            .filter(|(_, symbol)| symbol.demangled.as_deref() == Some(name));
+
+        match (found.next(), found.next()) {
+            (Some((_, symbol)), None) => Ok(self.address(symbol)),
+            (Some(_), Some(_)) => Err(SymbolError::Ambiguous(
+                self.symbols
+                    .iter()
+                    .filter(|(_, symbol)| symbol.demangled.as_deref() == Some(name))
+                    .map(|(mangled, _)| mangled.clone())
+                    .take(MAX_CANDIDATES)
+                    .collect(),
+            )),
+            _ => Err(SymbolError::NotFound(self.candidates(name))),
+        }
     }
 
     pub fn interface<T>(&self, name: &str) -> Result<*const T, InterfaceError> {
@@ -138,3 +438,253 @@ impl Module {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use procfs::process::{MMapExtension, MMapPath};
+
+    use super::*;
+
+    const SYMBOL: &str = "_ZN3Foo3BarEv";
+    const SYMBOL_VALUE: u32 = 0x1234;
+    const LOAD_ADDRESS: u64 = 0xf7a0_0000;
+
+    fn push_u16(buf: &mut Vec<u8>, value: u16) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, value: u32) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// x86 shared object with `r--`, `r-x` and `rw-` load segments, the last one starts
+    /// mid-page, and `.symtab` with single function `Foo::Bar` inside code segment.
+    /// All virtual addresses are shifted by `vaddr`.
+    fn fixture_elf(vaddr: u32) -> Vec<u8> {
+        const PHOFF: u32 = 0x34;
+        const STRTAB: u32 = 0x100;
+        const SYMTAB: u32 = 0x120;
+        const SHOFF: u32 = 0x140;
+
+        let mut buf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        buf.resize(16, 0);
+
+        push_u16(&mut buf, 3); // ET_DYN
+        push_u16(&mut buf, 3); // EM_386
+        push_u32(&mut buf, 1);
+        push_u32(&mut buf, 0); // entry
+        push_u32(&mut buf, PHOFF);
+        push_u32(&mut buf, SHOFF);
+        push_u32(&mut buf, 0); // flags
+        push_u16(&mut buf, 0x34);
+        push_u16(&mut buf, 0x20);
+        push_u16(&mut buf, 3);
+        push_u16(&mut buf, 0x28);
+        push_u16(&mut buf, 3);
+        push_u16(&mut buf, 0);
+
+        // offset, vaddr, size, flags
+        for (offset, segment_vaddr, size, flags) in [
+            (0x0000, 0x0000, 0x1000, 4),
+            (0x1000, 0x1000, 0x1000, 5),
+            (0x2f00, 0x3f00, 0x0100, 6),
+        ] {
+            push_u32(&mut buf, PT_LOAD);
+            push_u32(&mut buf, offset);
+            push_u32(&mut buf, vaddr + segment_vaddr);
+            push_u32(&mut buf, vaddr + segment_vaddr);
+            push_u32(&mut buf, size);
+            push_u32(&mut buf, size);
+            push_u32(&mut buf, flags);
+            push_u32(&mut buf, 0x1000);
+        }
+
+        buf.resize(STRTAB as usize, 0);
+        buf.push(0);
+        buf.extend_from_slice(SYMBOL.as_bytes());
+        buf.push(0);
+
+        // first symbol is always null
+        buf.resize(SYMTAB as usize + 0x10, 0);
+        push_u32(&mut buf, 1); // name
+        push_u32(&mut buf, vaddr + SYMBOL_VALUE);
+        push_u32(&mut buf, 0x10); // size
+        buf.push(0x12); // STB_GLOBAL, STT_FUNC
+        buf.push(0);
+        push_u16(&mut buf, 1); // section
+
+        buf.resize(SHOFF as usize + 0x28, 0);
+
+        // name, type, flags, addr, offset, size, link, info, align, entsize
+        for section in [
+            [0, 2, 0, 0, SYMTAB, 0x20, 2, 1, 4, 0x10],
+            [0, 3, 0, 0, STRTAB, 2 + SYMBOL.len() as u32, 0, 0, 1, 0],
+        ] {
+            for value in section {
+                push_u32(&mut buf, value);
+            }
+        }
+
+        buf.resize(0x3000, 0);
+
+        buf
+    }
+
+    fn write_fixture(name: &str, vaddr: u32) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("packuwus_{}_{}.so", std::process::id(), name));
+
+        fs::write(&path, fixture_elf(vaddr)).unwrap();
+
+        path
+    }
+
+    fn mem_map(path: &Path, start: u64, end: u64, perms: &str, offset: u64) -> MemoryMap {
+        let mut flags = MMPermissions::PRIVATE;
+
+        for (c, flag) in perms.chars().zip([
+            MMPermissions::READ,
+            MMPermissions::WRITE,
+            MMPermissions::EXECUTE,
+        ]) {
+            if c != '-' {
+                flags |= flag;
+            }
+        }
+
+        MemoryMap {
+            address: (start, end),
+            perms: flags,
+            offset,
+            dev: (8, 1),
+            inode: 42,
+            pathname: MMapPath::Path(path.to_owned()),
+            extension: MMapExtension::default(),
+        }
+    }
+
+    /// How the kernel maps fixture at [`LOAD_ADDRESS`]
+    fn fixture_maps(path: &Path) -> Vec<MemoryMap> {
+        vec![
+            mem_map(path, LOAD_ADDRESS, LOAD_ADDRESS + 0x1000, "r--", 0),
+            mem_map(
+                path,
+                LOAD_ADDRESS + 0x1000,
+                LOAD_ADDRESS + 0x2000,
+                "r-x",
+                0x1000,
+            ),
+            mem_map(
+                path,
+                LOAD_ADDRESS + 0x3000,
+                LOAD_ADDRESS + 0x4000,
+                "rw-",
+                0x2000,
+            ),
+        ]
+    }
+
+    #[test]
+    fn resolves_shared_object() {
+        let path = write_fixture("shared_object", 0);
+        let module = Module::from_mem_maps(&fixture_maps(&path)).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(module.path, path);
+        assert_eq!(module.load_bias, LOAD_ADDRESS);
+        assert_eq!(
+            module.symbol(SYMBOL).unwrap() as u64,
+            LOAD_ADDRESS + SYMBOL_VALUE as u64
+        );
+        assert_eq!(
+            module.symbol_demangled("Foo::Bar").unwrap() as u64,
+            LOAD_ADDRESS + SYMBOL_VALUE as u64
+        );
+    }
+
+    #[test]
+    fn resolves_linked_at_nonzero_address() {
+        let vaddr = 0x0804_8000;
+        let path = write_fixture("nonzero_vaddr", vaddr);
+        let module = Module::from_mem_maps(&fixture_maps(&path)).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(module.load_bias, LOAD_ADDRESS.wrapping_sub(vaddr as u64));
+        assert_eq!(
+            module.symbol(SYMBOL).unwrap() as u64,
+            LOAD_ADDRESS + SYMBOL_VALUE as u64
+        );
+    }
+
+    #[test]
+    fn keeps_segment_permissions() {
+        let path = write_fixture("segments", 0);
+        let module = Module::from_mem_maps(&fixture_maps(&path)).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        let executable: Vec<&Segment> = module
+            .segments
+            .iter()
+            .filter(|segment| segment.is_executable())
+            .collect();
+
+        assert_eq!(module.segments.len(), 3);
+        assert_eq!(executable.len(), 1);
+        assert_eq!(executable[0].start, LOAD_ADDRESS + 0x1000);
+        assert_eq!(executable[0].size, 0x1000);
+
+        let symbol = module.symbol(SYMBOL).unwrap() as u64;
+
+        assert!((executable[0].start..executable[0].start + executable[0].size).contains(&symbol));
+    }
+
+    #[test]
+    fn fails_without_first_load_segment_mapped() {
+        let path = write_fixture("unmapped", 0);
+        let maps = fixture_maps(&path);
+        let result = Module::from_mem_maps(&maps[1..]);
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(ModuleFromMemMapError::LoadSegmentNotMapped)
+        ));
+    }
+
+    #[test]
+    fn collects_maps_of_module_file() {
+        let path = PathBuf::from("/srv/garrysmod/bin/server_srv.so");
+        let other = PathBuf::from("/srv/garrysmod/bin/engine_srv.so");
+        let mut anonymous = mem_map(&path, 0x1000, 0x2000, "rw-", 0);
+
+        anonymous.pathname = MMapPath::Anonymous;
+        anonymous.inode = 0;
+
+        let maps = vec![
+            mem_map(&other, 0x1000, 0x2000, "r--", 0),
+            anonymous,
+            mem_map(&path, 0x3000, 0x4000, "r--", 0),
+            mem_map(&other, 0x4000, 0x5000, "r-x", 0x1000),
+            mem_map(&path, 0x5000, 0x6000, "r-x", 0x1000),
+            mem_map(&path, 0x7000, 0x8000, "rw-", 0x2000),
+        ];
+
+        let module_maps = Module::module_maps(&maps, "server_srv.so").unwrap();
+
+        assert_eq!(
+            module_maps
+                .iter()
+                .map(|map| map.address.0)
+                .collect::<Vec<_>>(),
+            [0x3000, 0x5000, 0x7000]
+        );
+
+        assert!(Module::module_maps(&maps, "client.so").is_none());
+    }
+}
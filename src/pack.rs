@@ -0,0 +1,791 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    mem::size_of,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compression::{CompressError, CompressionOptions},
+    dictionary::Dictionary,
+    packuwus::PackedFile,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BuildError {
+    #[error("{0}")]
+    Compress(CompressError),
+    #[error("Pack was cancelled")]
+    Cancelled,
+}
+
+pub const SERVE_DIRECTORY: &str = "data/serve_packuwus/";
+pub const CHUNK_COUNT: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    pub chunk_name: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Chunk {
+    pub entry: ManifestEntry,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Last full pack. Delta packs are built relative to it.
+#[derive(Debug)]
+pub struct BasePack {
+    pub manifest_name: String,
+    pub entries: Vec<ManifestEntry>,
+    pub file_hashes: HashMap<String, [u8; 32]>,
+    pub dictionary: Option<Dictionary>,
+}
+
+#[derive(Debug)]
+pub struct LayoutBenchmark {
+    pub solid: bool,
+    pub bytes: usize,
+    pub chunks: usize,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub struct Delta {
+    pub chunk: Option<Chunk>,
+    pub removed: Vec<String>,
+}
+
+/// Shared between pack worker and game thread, which reads it and may cancel the build
+#[derive(Debug, Default)]
+pub struct Progress {
+    files: AtomicUsize,
+    total: AtomicUsize,
+    bytes: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressState {
+    /// Files compressed so far
+    pub files: usize,
+    /// Files to compress
+    pub total: usize,
+    /// Compressed bytes written so far
+    pub bytes: usize,
+}
+
+impl Progress {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> ProgressState {
+        ProgressState {
+            files: self.files.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn check_cancelled(&self) -> Result<(), BuildError> {
+        if self.is_cancelled() {
+            Err(BuildError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Starts counting anew, e.g. when rejected delta is rebuilt as full pack
+    fn begin(&self, total: usize) {
+        self.files.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    fn add_files(&self, files: usize) {
+        self.files.fetch_add(files, Ordering::Relaxed);
+    }
+
+    fn add_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+pub fn served_path(name: &str) -> String {
+    format!("{}{}.bsp", SERVE_DIRECTORY, name)
+}
+
+fn content_name(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn content_hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+// stable across restarts, so unchanged buckets keep their chunk names
+fn bucket_of(path: &str, bucket_count: usize) -> usize {
+    let hash = Sha256::digest(path.as_bytes());
+
+    u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) as usize % bucket_count
+}
+
+const LAYOUT_PER_FILE: u8 = 0;
+const LAYOUT_SOLID: u8 = 1;
+
+pub fn train_dictionary(files: &HashMap<String, PackedFile>) -> Dictionary {
+    Dictionary::train(files.values().map(|file| file.content.as_slice()))
+}
+
+fn encode_content<'a>(file: &'a PackedFile, dictionary: Option<&Dictionary>) -> Cow<'a, [u8]> {
+    match dictionary {
+        Some(dictionary) => Cow::Owned(dictionary.encode(&file.content)),
+        None => Cow::Borrowed(file.content.as_slice()),
+    }
+}
+
+/// Chunk layout:
+///
+/// ```text
+/// u8  layout (0 = per file, 1 = solid)
+/// u8  codec id
+/// u8  codec level
+/// u8  1 if contents are encoded with manifest's dictionary
+///
+/// per file layout, repeated till EOF:
+///   path \0
+///   u8   codec id (files smaller than threshold are stored as is)
+///   u32  size
+///   compressed content
+///
+/// solid layout:
+///   u32  files count
+///   index, files count times:
+///     path \0
+///     u32  offset in decompressed stream
+///     u32  size
+///   u32  compressed stream size
+///   compressed stream of concatenated file contents
+/// ```
+fn encode_chunk(
+    files: &[(&String, &PackedFile)],
+    options: &CompressionOptions,
+    dictionary: Option<&Dictionary>,
+    progress: &Progress,
+) -> Result<Vec<u8>, BuildError> {
+    let mut buf = vec![
+        if options.solid {
+            LAYOUT_SOLID
+        } else {
+            LAYOUT_PER_FILE
+        },
+        options.codec.id(),
+        options.codec.level(),
+        dictionary.is_some() as u8,
+    ];
+
+    if options.solid {
+        let mut stream = Vec::with_capacity(
+            files
+                .iter()
+                .map(|(_, file)| file.content.len())
+                .sum::<usize>(),
+        );
+
+        buf.extend_from_slice(&(files.len() as u32).to_le_bytes());
+
+        for (path, file) in files {
+            progress.check_cancelled()?;
+
+            let content = encode_content(file, dictionary);
+
+            buf.extend_from_slice(path.as_bytes());
+            buf.extend_from_slice(&[0]);
+            buf.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(content.len() as u32).to_le_bytes());
+
+            stream.extend_from_slice(&content);
+        }
+
+        let compressed = options
+            .codec
+            .compress(&stream)
+            .or_else(|err| Err(BuildError::Compress(err)))?;
+
+        progress.add_files(files.len());
+        progress.add_bytes(compressed.len());
+
+        buf.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&compressed);
+    } else {
+        for (path, file) in files {
+            progress.check_cancelled()?;
+
+            let content = encode_content(file, dictionary);
+            let codec = options.codec_for(&content);
+            let compressed = codec
+                .compress(&content)
+                .or_else(|err| Err(BuildError::Compress(err)))?;
+
+            buf.reserve(path.len() + 1 + 1 + size_of::<u32>() + compressed.len());
+            buf.extend_from_slice(path.as_bytes());
+            buf.extend_from_slice(&[0, codec.id()]);
+            buf.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&compressed);
+
+            progress.add_files(1);
+            progress.add_bytes(compressed.len());
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Splits files into [`CHUNK_COUNT`] hash buckets. Every chunk is named after its
+/// content hash, so only buckets with changed files get a new name.
+/// Solid packs are a single chunk, whole archive is one compressed stream then.
+pub fn build_chunks(
+    files: &HashMap<String, PackedFile>,
+    options: &CompressionOptions,
+    dictionary: Option<&Dictionary>,
+    progress: &Progress,
+) -> Result<Vec<Chunk>, BuildError> {
+    progress.begin(files.len());
+
+    let bucket_count = if options.solid { 1 } else { CHUNK_COUNT };
+    let mut buckets: Vec<Vec<(&String, &PackedFile)>> = vec![vec![]; bucket_count];
+
+    for file in files.iter() {
+        buckets[bucket_of(file.0, bucket_count)].push(file);
+    }
+
+    buckets
+        .into_iter()
+        .filter(|bucket| !bucket.is_empty())
+        .map(|mut bucket| {
+            bucket.sort_by(|a, b| a.0.cmp(b.0));
+
+            build_chunk(&bucket, options, dictionary, progress)
+        })
+        .collect()
+}
+
+/// Builds the same files in per file and solid layouts with current codec
+pub fn benchmark_layouts(
+    files: &HashMap<String, PackedFile>,
+    options: &CompressionOptions,
+) -> Result<Vec<LayoutBenchmark>, BuildError> {
+    [false, true]
+        .into_iter()
+        .map(|solid| {
+            let start = Instant::now();
+            let dictionary = options.dictionary.then(|| train_dictionary(files));
+            let chunks = build_chunks(
+                files,
+                &CompressionOptions { solid, ..*options },
+                dictionary.as_ref(),
+                &Progress::default(),
+            )?;
+
+            Ok(LayoutBenchmark {
+                solid,
+                bytes: chunks.iter().map(|chunk| chunk.data.len()).sum(),
+                chunks: chunks.len(),
+                duration: start.elapsed(),
+            })
+        })
+        .collect()
+}
+
+fn build_chunk(
+    files: &[(&String, &PackedFile)],
+    options: &CompressionOptions,
+    dictionary: Option<&Dictionary>,
+    progress: &Progress,
+) -> Result<Chunk, BuildError> {
+    let data = encode_chunk(files, options, dictionary, progress)?;
+
+    Ok(Chunk {
+        entry: ManifestEntry {
+            chunk_name: content_name(&data),
+            paths: files.iter().map(|(path, _)| path.to_string()).collect(),
+        },
+        data,
+    })
+}
+
+impl BasePack {
+    pub fn new(
+        manifest_name: String,
+        chunks: &[Chunk],
+        files: &HashMap<String, PackedFile>,
+        dictionary: Option<Dictionary>,
+    ) -> BasePack {
+        BasePack {
+            manifest_name,
+            entries: chunks.iter().map(|chunk| chunk.entry.clone()).collect(),
+            file_hashes: files
+                .iter()
+                .map(|(path, file)| (path.clone(), content_hash(&file.content)))
+                .collect(),
+            dictionary,
+        }
+    }
+
+    /// Collects every file that was changed, added or removed since this base pack.
+    /// Changes are cumulative, so a client only ever needs the base and the latest delta.
+    pub fn build_delta(
+        &self,
+        files: &HashMap<String, PackedFile>,
+        options: &CompressionOptions,
+        progress: &Progress,
+    ) -> Result<Delta, BuildError> {
+        let mut changed: Vec<(&String, &PackedFile)> = files
+            .iter()
+            .filter(|(path, file)| {
+                self.file_hashes.get(*path) != Some(&content_hash(&file.content))
+            })
+            .collect();
+
+        changed.sort_by(|a, b| a.0.cmp(b.0));
+
+        progress.begin(changed.len());
+
+        let mut removed: Vec<String> = self
+            .file_hashes
+            .keys()
+            .filter(|path| !files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        removed.sort();
+
+        Ok(Delta {
+            chunk: if changed.is_empty() {
+                None
+            } else {
+                Some(build_chunk(
+                    &changed,
+                    options,
+                    self.dictionary.as_ref(),
+                    progress,
+                )?)
+            },
+            removed,
+        })
+    }
+}
+
+impl Delta {
+    pub fn files_count(&self) -> usize {
+        self.removed.len()
+            + self
+                .chunk
+                .as_ref()
+                .map(|chunk| chunk.entry.paths.len())
+                .unwrap_or(0)
+    }
+}
+
+/// Manifest layout:
+///
+/// ```text
+/// base name \0   (empty if this is a full pack)
+/// u32            chunks count
+///   chunk name \0
+///   u32          files count
+///   path \0      (files count times)
+/// u32            removed files count
+/// path \0        (removed files count times)
+/// u32            dictionary entries count, 0 if chunks aren't dictionary encoded
+/// entry \0       (dictionary entries count times)
+/// u32            virtual files count, lists every virtual file of the pack including base
+/// path \0        (virtual files count times)
+/// ```
+pub fn build_manifest(
+    base_name: Option<&str>,
+    entries: &[&ManifestEntry],
+    removed: &[String],
+    dictionary: Option<&Dictionary>,
+    virtual_paths: &[String],
+) -> Manifest {
+    let mut buf = vec![];
+
+    buf.extend_from_slice(base_name.unwrap_or("").as_bytes());
+    buf.extend_from_slice(&[0]);
+    buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+
+    for entry in entries {
+        buf.extend_from_slice(entry.chunk_name.as_bytes());
+        buf.extend_from_slice(&[0]);
+        buf.extend_from_slice(&(entry.paths.len() as u32).to_le_bytes());
+
+        for path in entry.paths.iter() {
+            buf.extend_from_slice(path.as_bytes());
+            buf.extend_from_slice(&[0]);
+        }
+    }
+
+    buf.extend_from_slice(&(removed.len() as u32).to_le_bytes());
+
+    for path in removed {
+        buf.extend_from_slice(path.as_bytes());
+        buf.extend_from_slice(&[0]);
+    }
+
+    let dictionary_entries = dictionary
+        .map(|dictionary| dictionary.entries.as_slice())
+        .unwrap_or_default();
+
+    buf.extend_from_slice(&(dictionary_entries.len() as u32).to_le_bytes());
+
+    for entry in dictionary_entries {
+        buf.extend_from_slice(entry);
+        buf.extend_from_slice(&[0]);
+    }
+
+    buf.extend_from_slice(&(virtual_paths.len() as u32).to_le_bytes());
+
+    for path in virtual_paths {
+        buf.extend_from_slice(path.as_bytes());
+        buf.extend_from_slice(&[0]);
+    }
+
+    Manifest {
+        name: content_name(&buf),
+        data: buf,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::*;
+    use crate::compression::Codec;
+
+    /// Smoke test corpus: PackUwUs own GLua files
+    fn lua_corpus() -> HashMap<String, PackedFile> {
+        [
+            (
+                "cl_debug_helpers.lua",
+                &include_bytes!("../lua/packuwus/cl_debug_helpers.lua")[..],
+            ),
+            ("cl_impl.lua", include_bytes!("../lua/packuwus/cl_impl.lua")),
+            ("cl_main.lua", include_bytes!("../lua/packuwus/cl_main.lua")),
+            (
+                "cl_startup.lua",
+                include_bytes!("../lua/packuwus/cl_startup.lua"),
+            ),
+            ("sh_main.lua", include_bytes!("../lua/packuwus/sh_main.lua")),
+            (
+                "sh_utils.lua",
+                include_bytes!("../lua/packuwus/sh_utils.lua"),
+            ),
+            (
+                "sv_debug_helpers.lua",
+                include_bytes!("../lua/packuwus/sv_debug_helpers.lua"),
+            ),
+            ("sv_impl.lua", include_bytes!("../lua/packuwus/sv_impl.lua")),
+            ("sv_main.lua", include_bytes!("../lua/packuwus/sv_main.lua")),
+            (
+                "sv_startup.lua",
+                include_bytes!("../lua/packuwus/sv_startup.lua"),
+            ),
+            (
+                "sv_utils.lua",
+                include_bytes!("../lua/packuwus/sv_utils.lua"),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, content)| {
+            (
+                format!("lua/packuwus/{}", name),
+                PackedFile {
+                    content: content.to_vec(),
+                    is_virtual: false,
+                },
+            )
+        })
+        .collect()
+    }
+
+    fn read_string(data: &[u8], pos: &mut usize) -> String {
+        let len = data[*pos..].iter().position(|b| *b == 0).unwrap();
+        let string = String::from_utf8(data[*pos..*pos + len].to_vec()).unwrap();
+
+        *pos += len + 1;
+
+        string
+    }
+
+    fn read_u32(data: &[u8], pos: &mut usize) -> usize {
+        let value = u32::from_le_bytes(data[*pos..*pos + 4].try_into().unwrap());
+
+        *pos += 4;
+
+        value as usize
+    }
+
+    /// Mirrors `PackUwUs.UnpackChunk` in cl_main.lua
+    fn unpack_chunk(data: &[u8]) -> HashMap<String, Vec<u8>> {
+        let mut files = HashMap::new();
+        let mut pos = 4;
+
+        if data[0] == LAYOUT_SOLID {
+            let count = read_u32(data, &mut pos);
+            let index: Vec<(String, usize, usize)> = (0..count)
+                .map(|_| {
+                    let path = read_string(data, &mut pos);
+                    let offset = read_u32(data, &mut pos);
+                    let size = read_u32(data, &mut pos);
+
+                    (path, offset, size)
+                })
+                .collect();
+
+            let size = read_u32(data, &mut pos);
+            let stream = match data[1] {
+                0 => data[pos..pos + size].to_vec(),
+                _ => gmod_lzma::decompress(&data[pos..pos + size]).unwrap(),
+            };
+
+            for (path, offset, size) in index {
+                files.insert(path, stream[offset..offset + size].to_vec());
+            }
+        } else {
+            while pos < data.len() {
+                let path = read_string(data, &mut pos);
+                let codec = data[pos];
+
+                pos += 1;
+
+                let size = read_u32(data, &mut pos);
+                let content = &data[pos..pos + size];
+
+                pos += size;
+
+                files.insert(
+                    path,
+                    match codec {
+                        0 => content.to_vec(),
+                        _ => gmod_lzma::decompress(content).unwrap(),
+                    },
+                );
+            }
+        }
+
+        files
+    }
+
+    pub(crate) fn unpack_all(chunks: &[Chunk]) -> HashMap<String, Vec<u8>> {
+        chunks
+            .iter()
+            .flat_map(|chunk| unpack_chunk(&chunk.data))
+            .collect()
+    }
+
+    fn options(solid: bool) -> CompressionOptions {
+        CompressionOptions {
+            solid,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn solid_pack_is_single_chunk() {
+        let files = lua_corpus();
+        let chunks = build_chunks(&files, &options(true), None, &Progress::default()).unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data[0], LAYOUT_SOLID);
+        assert_eq!(chunks[0].entry.paths.len(), files.len());
+    }
+
+    #[test]
+    fn layouts_unpack_to_same_files() {
+        let files = lua_corpus();
+        let expected: HashMap<String, Vec<u8>> = files
+            .iter()
+            .map(|(path, file)| (path.clone(), file.content.clone()))
+            .collect();
+
+        for solid in [false, true] {
+            let chunks = build_chunks(&files, &options(solid), None, &Progress::default()).unwrap();
+
+            assert_eq!(unpack_all(&chunks), expected, "solid = {}", solid);
+        }
+    }
+
+    #[test]
+    fn uncompressed_files_are_stored_as_is() {
+        let files = lua_corpus();
+        let options = CompressionOptions {
+            min_size: 1000,
+            ..Default::default()
+        };
+        let chunks = build_chunks(&files, &options, None, &Progress::default()).unwrap();
+
+        assert_eq!(unpack_all(&chunks).len(), files.len());
+
+        let options = CompressionOptions {
+            codec: Codec::None,
+            solid: true,
+            ..Default::default()
+        };
+        let chunks = build_chunks(&files, &options, None, &Progress::default()).unwrap();
+
+        assert_eq!(
+            unpack_all(&chunks)["lua/packuwus/sv_impl.lua"],
+            files["lua/packuwus/sv_impl.lua"].content
+        );
+    }
+
+    #[test]
+    fn pinned_dictionary_keeps_untouched_chunk_names() {
+        let mut files = lua_corpus();
+        let dictionary = train_dictionary(&files);
+        let before = build_chunks(
+            &files,
+            &options(false),
+            Some(&dictionary),
+            &Progress::default(),
+        )
+        .unwrap();
+
+        let added = "lua/autorun/client/added.lua".to_string();
+
+        files.insert(
+            added.clone(),
+            PackedFile {
+                content: b"hook.Add(\"Think\", \"added\", function() end)".to_vec(),
+                is_virtual: false,
+            },
+        );
+
+        let after = build_chunks(
+            &files,
+            &options(false),
+            Some(&dictionary),
+            &Progress::default(),
+        )
+        .unwrap();
+
+        for chunk in after {
+            if !chunk.entry.paths.contains(&added) {
+                assert!(before
+                    .iter()
+                    .any(|old| old.entry.chunk_name == chunk.entry.chunk_name));
+            }
+        }
+    }
+
+    /// Run with `cargo test benchmark -- --nocapture` to see timings
+    fn print_layout_benchmark(files: &HashMap<String, PackedFile>) -> Vec<LayoutBenchmark> {
+        let results = benchmark_layouts(files, &options(false)).unwrap();
+
+        for result in results.iter() {
+            let chunks =
+                build_chunks(files, &options(result.solid), None, &Progress::default()).unwrap();
+            let start = Instant::now();
+            let unpacked = unpack_all(&chunks);
+
+            println!(
+                "solid = {}: {} files, {} bytes in {} chunks, packed in {:?}, unpacked in {:?}",
+                result.solid,
+                unpacked.len(),
+                result.bytes,
+                result.chunks,
+                result.duration,
+                start.elapsed()
+            );
+        }
+
+        results
+    }
+
+    /// Every `.lua` file under `directory`, keyed by path relative to `root`
+    fn read_lua_files(root: &Path, directory: &Path, files: &mut HashMap<String, PackedFile>) {
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                read_lua_files(root, &path, files);
+            } else if path.extension().is_some_and(|ext| ext == "lua") {
+                let key = path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/");
+
+                files.insert(
+                    key,
+                    PackedFile {
+                        content: fs::read(&path).unwrap(),
+                        is_virtual: false,
+                    },
+                );
+            }
+        }
+    }
+
+    // bundled files are too few and too alike to compare layouts, this only checks it runs
+    #[test]
+    fn benchmarks_layouts_on_bundled_files() {
+        let results = print_layout_benchmark(&lua_corpus());
+
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .any(|result| result.solid && result.chunks == 1));
+    }
+
+    /// Compares layouts on a real addon corpus, e.g. `garrysmod/addons`:
+    /// `PACKUWUS_BENCH_CORPUS=<dir> cargo test benchmarks_layouts_on_corpus -- --nocapture`
+    #[test]
+    fn benchmarks_layouts_on_corpus() {
+        let Some(corpus) = env::var_os("PACKUWUS_BENCH_CORPUS") else {
+            println!("PACKUWUS_BENCH_CORPUS isn't set, skipping");
+
+            return;
+        };
+
+        let root = PathBuf::from(corpus);
+        let mut files = HashMap::new();
+
+        read_lua_files(&root, &root, &mut files);
+
+        assert!(!files.is_empty(), "no .lua files in {}", root.display());
+
+        let results = print_layout_benchmark(&files);
+
+        for result in results {
+            let chunks =
+                build_chunks(&files, &options(result.solid), None, &Progress::default()).unwrap();
+            let unpacked = unpack_all(&chunks);
+
+            assert_eq!(unpacked.len(), files.len());
+            assert!(files
+                .iter()
+                .all(|(path, file)| unpacked.get(path) == Some(&file.content)));
+        }
+    }
+}
@@ -1,36 +1,50 @@
 use std::{
-    collections::HashMap,
-    ffi::{CString, NulError},
-    io::Write,
-    mem::size_of,
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString, NulError},
     ptr::copy_nonoverlapping,
-    string::FromUtf8Error,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use gmod::lua::{State, LUA_GLOBALSINDEX};
 use gmod_lzma::SZ;
 use sha2::{Digest, Sha256};
-use uuid::Uuid;
 
-use crate::sdk::{
-    filesystem::{ReadFileError, WrappedFileSystem, WriteFileError},
-    networkstringtable::WrappedNetworkStringTable,
+use crate::{
+    compression::CompressionOptions,
+    dictionary::Dictionary,
+    pack::{
+        benchmark_layouts, build_chunks, build_manifest, served_path, train_dictionary, BasePack,
+        BuildError, Chunk, LayoutBenchmark, Manifest, Progress, SERVE_DIRECTORY,
+    },
+    sdk::{
+        filesystem::{FileSystem, ReadFileError, WriteFileError},
+        networkstringtable::WrappedNetworkStringTable,
+    },
 };
 
+/// Seconds unused served files are kept for, see [`PackUwUs::collect_served_garbage`]
+const SERVED_FILE_LIFETIME: i64 = 60 * 60;
+
+/// Stable error codes passed to Lua as `{code, message, path}` tables.
+/// Tooling alerts on them, so existing codes must never change.
+pub trait ErrorCode: std::error::Error {
+    fn code(&self) -> &'static str;
+
+    /// File the error is about, if error itself knows it
+    fn path(&self) -> Option<&str> {
+        None
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum BuildLuaDownloadPacketError {
-    #[error("Lua code contains \\0 byte, what a mistake!")]
-    LuaCodeContainsNul(NulError),
     #[error("Failed to compress lua code: {0}")]
     CompressFailed(SZ),
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum BuildLuaAutoRefreshPacketError {
-    #[error("File path contains \\0 byte")]
-    FilepathContainsNul(NulError),
-    #[error("Lua code contains \\0 byte, what a mistake!")]
-    LuaCodeContainsNul(NulError),
     #[error("Failed to compress lua code: {0}")]
     CompressFailed(SZ),
 }
@@ -53,8 +67,6 @@ pub enum AddFileError {
     PathContainsNul(NulError),
     #[error("Failed to read file")]
     ReadFailed(ReadFileError),
-    #[error("Failed to convert readed file contents as UTF-8: {0}")]
-    FromUtf8Failed(FromUtf8Error),
     #[error("File already exists")]
     Exists,
 }
@@ -65,34 +77,219 @@ pub enum EditFileError {
     DontExist,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum RemoveFileError {
+    #[error("File doesn't exist")]
+    DontExist,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum TryServeError {
-    #[error("Failed to write packed file: {0}")]
-    WriteFileFailed(WriteFileError),
+    #[error("Failed to write packed file {0}: {1}")]
+    WriteFileFailed(String, WriteFileError),
     #[error("Packed contents is not set. Forgot to set it using PackUwUs_SetPackContent?")]
     PackedContentsNotSet,
+    #[error("Failed to build pack: {0}")]
+    BuildFailed(BuildError),
+    #[error("Nothing to pack")]
+    NothingToPack,
+    #[error("Can't pack synchronously while async pack is running")]
+    AsyncPackRunning,
 }
 
-#[derive(Debug)]
+#[derive(thiserror::Error, Debug)]
+pub enum DrainError {
+    #[error("Delivering async pack panicked, see crash report in data/packuwus")]
+    Panicked,
+}
+
+impl ErrorCode for BuildLuaDownloadPacketError {
+    fn code(&self) -> &'static str {
+        match self {
+            BuildLuaDownloadPacketError::CompressFailed(_) => "compress_failed",
+        }
+    }
+}
+
+impl ErrorCode for BuildLuaAutoRefreshPacketError {
+    fn code(&self) -> &'static str {
+        match self {
+            BuildLuaAutoRefreshPacketError::CompressFailed(_) => "compress_failed",
+        }
+    }
+}
+
+impl ErrorCode for HandlePackError {
+    fn code(&self) -> &'static str {
+        match self {
+            HandlePackError::NoGlobalFunc => "handle_pack_missing",
+            HandlePackError::LuaErrorOccured => "handle_pack_errored",
+            HandlePackError::InvalidReturnValue(_) => "handle_pack_invalid_return",
+        }
+    }
+}
+
+impl ErrorCode for AddFileError {
+    fn code(&self) -> &'static str {
+        match self {
+            AddFileError::PathContainsNul(_) => "path_contains_nul",
+            AddFileError::ReadFailed(_) => "read_failed",
+            AddFileError::Exists => "file_exists",
+        }
+    }
+}
+
+impl ErrorCode for EditFileError {
+    fn code(&self) -> &'static str {
+        match self {
+            EditFileError::DontExist => "file_not_found",
+        }
+    }
+}
+
+impl ErrorCode for RemoveFileError {
+    fn code(&self) -> &'static str {
+        match self {
+            RemoveFileError::DontExist => "file_not_found",
+        }
+    }
+}
+
+impl ErrorCode for DrainError {
+    fn code(&self) -> &'static str {
+        match self {
+            DrainError::Panicked => "panicked",
+        }
+    }
+}
+
+impl ErrorCode for TryServeError {
+    fn code(&self) -> &'static str {
+        match self {
+            TryServeError::WriteFileFailed(..) => "write_failed",
+            TryServeError::PackedContentsNotSet => "pack_content_not_set",
+            TryServeError::BuildFailed(BuildError::Compress(_)) => "compress_failed",
+            TryServeError::BuildFailed(BuildError::Cancelled) => "cancelled",
+            TryServeError::NothingToPack => "nothing_to_pack",
+            TryServeError::AsyncPackRunning => "pack_running",
+        }
+    }
+
+    fn path(&self) -> Option<&str> {
+        match self {
+            TryServeError::WriteFileFailed(path, _) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// GMod hashes and compresses Lua code together with terminating \0, even if code has
+/// \0 bytes inside
+fn code_with_nul(code: &[u8]) -> Vec<u8> {
+    let mut code_with_nul = Vec::with_capacity(code.len() + 1);
+
+    code_with_nul.extend_from_slice(code);
+    code_with_nul.push(0);
+
+    code_with_nul
+}
+
+#[derive(Debug, Clone)]
 pub struct PackedFile {
-    pub content: String,
+    /// Raw bytes, Lua files aren't guaranteed to be UTF-8
+    pub content: Vec<u8>,
+    /// Added from Lua, there's no such file on disk nor in client_lua_files
+    pub is_virtual: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PackOptions {
+    /// Publish patch packs relative to the last full pack instead of repacking everything
+    pub delta: bool,
+    /// Read client files the pack misses from disk while reconciling
+    pub ingest_missing: bool,
+    pub compression: CompressionOptions,
+}
+
+/// Auto-refreshed file between `GModDataPack::AddOrUpdateFile` and sending it to clients
+#[derive(Debug)]
+pub struct RefreshedFile {
+    /// Engine's compressed buffer of the file, auto-refresh packet carries it
+    pub compressed: Vec<u8>,
+    /// Code `PackUwUs_HandlePack` replaced contents with
+    pub new_code: Option<Vec<u8>>,
+}
+
+/// Everything a pack is built from, taken on game thread so worker never touches live state
+#[derive(Debug)]
+pub struct PackSnapshot {
+    files: HashMap<String, PackedFile>,
+    options: PackOptions,
+    base: Option<Arc<BasePack>>,
+    dictionary: Option<Dictionary>,
+}
+
+/// Pack built off game thread, waiting to be written and served by [`PackUwUs::publish`]
+#[derive(Debug)]
+pub struct BuiltPack {
+    manifest: Manifest,
+    chunks: Vec<Chunk>,
+    /// Already written files the manifest depends on, e.g. base pack of delta
+    base_names: Vec<String>,
+    new_base: Option<BasePack>,
+    paths: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PackStats {
+    /// Files in the pack
+    pub files: usize,
+    /// Chunks built by this pack, delta reuses chunks of its base
+    pub chunks: usize,
+    /// Size of built chunks and manifest
+    pub bytes: usize,
+    pub delta: bool,
+}
+
+/// Difference between packed files and files clients download, see [`PackUwUs::reconcile`]
+#[derive(Debug, Default, Clone)]
+pub struct ReconcileReport {
+    /// Sent to clients, but not packed, so clients download them separately
+    pub unpacked: Vec<String>,
+    /// Packed, but not sent to clients. Virtual files aren't listed.
+    pub orphaned: Vec<String>,
+    /// Were unpacked, read from disk and added to pack
+    pub ingested: Vec<String>,
 }
 
 #[derive(Debug)]
 pub struct PackUwUs {
     lua: State,
-    fs: WrappedFileSystem,
+    fs: Box<dyn FileSystem>,
     downloadables: WrappedNetworkStringTable,
     client_lua_files: WrappedNetworkStringTable,
     files: HashMap<String, PackedFile>,
+    base: Option<Arc<BasePack>>,
+    /// Trained by the first dictionary encoded pack and reused by later ones, so chunks of
+    /// unchanged files keep their names. See [`PackUwUs::retrain_dictionary`].
+    dictionary: Option<Dictionary>,
+    /// Client files count at the last reconcile, string tables only grow
+    reconciled_strings: i32,
+    pub reconcile_report: ReconcileReport,
+    /// Removed once auto-refresh packet of the file is sent
+    pub refreshed_files: HashMap<String, RefreshedFile>,
+    pub options: PackOptions,
     pub content_changed: bool,
-    pub packed_contents: Option<String>,
+    pub packed_contents: Option<Vec<u8>>,
 }
 
+// Engine pointers inside are only used on game thread, pack workers get a PackSnapshot instead
+unsafe impl Send for PackUwUs {}
+
 impl PackUwUs {
     pub fn new(
         lua: State,
-        fs: WrappedFileSystem,
+        fs: Box<dyn FileSystem>,
         downloadables: WrappedNetworkStringTable,
         client_lua_files: WrappedNetworkStringTable,
     ) -> PackUwUs {
@@ -102,49 +299,60 @@ impl PackUwUs {
             downloadables,
             client_lua_files,
             files: HashMap::new(),
+            base: None,
+            dictionary: None,
+            reconciled_strings: 0,
+            reconcile_report: ReconcileReport::default(),
+            refreshed_files: HashMap::new(),
+            options: PackOptions::default(),
             content_changed: false,
             packed_contents: None,
         }
     }
 
+    pub fn lua(&self) -> State {
+        self.lua
+    }
+
+    /// Calls `_G.PackUwUs_HandlePack`. Lua may call back into the module,
+    /// so this must not be called while the state is locked.
     pub fn handle_pack(
-        &self,
+        lua: State,
         filepath: &str,
-        content: &str,
-    ) -> Result<(bool, Option<String>), HandlePackError> {
+        content: &[u8],
+    ) -> Result<(bool, Option<Vec<u8>>), HandlePackError> {
         let mut should_pack = false;
         let mut new_content = None;
 
         unsafe {
-            self.lua
-                .get_field(LUA_GLOBALSINDEX, c"PackUwUs_HandlePack".as_ptr());
+            lua.get_field(LUA_GLOBALSINDEX, c"PackUwUs_HandlePack".as_ptr());
 
-            if !self.lua.is_function(-1) {
-                self.lua.pop(); // pop function
+            if !lua.is_function(-1) {
+                lua.pop(); // pop function
 
                 return Err(HandlePackError::NoGlobalFunc);
             }
 
-            self.lua.push_string(filepath);
-            self.lua.push_string(content);
+            lua.push_string(filepath);
+            lua.push_binary_string(content);
 
-            if self.lua.pcall_ignore(2, 1) {
-                if self.lua.is_boolean(-1) {
-                    should_pack = self.lua.get_boolean(-1);
-                } else if self.lua.get_type(-1) == "string" {
+            if lua.pcall_ignore(2, 1) {
+                if lua.is_boolean(-1) {
+                    should_pack = lua.get_boolean(-1);
+                } else if lua.get_type(-1) == "string" {
                     should_pack = true;
-                    new_content = Some(self.lua.get_string(-1).unwrap().to_string());
+                    new_content = Some(lua.get_binary_string(-1).unwrap().to_vec());
                 } else {
-                    self.lua.pop(); // pop return value
+                    lua.pop(); // pop return value
 
                     return Err(HandlePackError::InvalidReturnValue(
-                        self.lua.get_type(-1).to_string(),
+                        lua.get_type(-1).to_string(),
                     ));
                 }
 
-                self.lua.pop(); // pop return value
+                lua.pop(); // pop return value
             } else {
-                self.lua.pop(); // pop function
+                lua.pop(); // pop function
 
                 return Err(HandlePackError::LuaErrorOccured);
             }
@@ -156,7 +364,7 @@ impl PackUwUs {
     pub fn add_file(
         &mut self,
         path: &str,
-        new_content: Option<String>,
+        new_content: Option<Vec<u8>>,
     ) -> Result<(), AddFileError> {
         if self.files.contains_key(path) {
             return Err(AddFileError::Exists);
@@ -165,31 +373,215 @@ impl PackUwUs {
         let content = if let Some(new_content) = new_content {
             new_content
         } else {
-            String::from_utf8(
-                self.fs
-                    .read_file(
-                        CString::new(path)
-                            .or_else(|err| Err(AddFileError::PathContainsNul(err)))?
-                            .as_c_str(),
-                        Some(c"GAME"),
-                    )
-                    .or_else(|err| Err(AddFileError::ReadFailed(err)))?,
-            )
-            .or_else(|err| Err(AddFileError::FromUtf8Failed(err)))?
+            self.read_file(path)?
         };
 
         self.content_changed = true;
 
-        self.files.insert(path.to_string(), PackedFile { content });
+        self.files.insert(
+            path.to_string(),
+            PackedFile {
+                content,
+                is_virtual: false,
+            },
+        );
 
         Ok(())
     }
 
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, AddFileError> {
+        self.fs
+            .read_file(
+                CString::new(path)
+                    .or_else(|err| Err(AddFileError::PathContainsNul(err)))?
+                    .as_c_str(),
+                Some(c"GAME"),
+            )
+            .or_else(|err| Err(AddFileError::ReadFailed(err)))
+    }
+
     pub fn is_packed(&self, path: &str) -> bool {
         self.files.contains_key(path)
     }
 
-    pub fn edit_file(&mut self, path: &str, new_content: String) -> Result<(), EditFileError> {
+    /// Sorted paths of every packed file
+    pub fn file_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.files.keys().cloned().collect();
+
+        paths.sort();
+
+        paths
+    }
+
+    pub fn file(&self, path: &str) -> Option<&PackedFile> {
+        self.files.get(path)
+    }
+
+    /// Adds file with given content or replaces content of existing one, nothing is read from disk
+    pub fn add_virtual_file(&mut self, path: &str, content: Vec<u8>) {
+        match self.files.get_mut(path) {
+            Some(file) if file.content == content => (),
+            Some(file) => {
+                self.content_changed = true;
+
+                file.content = content;
+            }
+            None => {
+                self.content_changed = true;
+
+                self.files.insert(
+                    path.to_string(),
+                    PackedFile {
+                        content,
+                        is_virtual: true,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> Result<(), RemoveFileError> {
+        if self.files.contains_key(path) {
+            self.remove_files(&[path.to_string()]);
+
+            Ok(())
+        } else {
+            Err(RemoveFileError::DontExist)
+        }
+    }
+
+    /// Paths of every file clients are told to download
+    fn client_file_paths(&self) -> HashSet<String> {
+        (0..self.client_lua_files.num_strings())
+            .filter_map(|index| self.client_lua_files.string(index))
+            .map(|path| path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// Whether files or client files changed since the last reconcile
+    pub fn needs_reconcile(&self) -> bool {
+        self.content_changed || self.client_lua_files.num_strings() != self.reconciled_strings
+    }
+
+    /// Lists client files the pack misses and packed files clients don't download.
+    /// Ingesting is left to the caller, it has to go through `_G.PackUwUs_HandlePack`.
+    pub fn reconcile(&mut self) -> ReconcileReport {
+        let client_files = self.client_file_paths();
+
+        let mut unpacked: Vec<String> = client_files
+            .iter()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        let mut orphaned: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(path, file)| !file.is_virtual && !client_files.contains(*path))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        unpacked.sort();
+        orphaned.sort();
+
+        self.reconciled_strings = self.client_lua_files.num_strings();
+
+        ReconcileReport {
+            unpacked,
+            orphaned,
+            ingested: Vec::new(),
+        }
+    }
+
+    fn exists_on_disk(&self, path: &str) -> bool {
+        // can't be checked, so better keep it
+        let Ok(c_path) = CString::new(path) else {
+            return true;
+        };
+
+        self.fs.exists(c_path.as_c_str(), Some(c"GAME"))
+    }
+
+    /// Gives removed files hashes of their own content back. Clients that cached the unpack
+    /// stub would keep loading it and look for the file in a pack that no longer has it.
+    fn restore_client_hashes(&self, removed: &HashMap<String, PackedFile>) {
+        if removed.is_empty() {
+            return;
+        }
+
+        for index in 0..self.client_lua_files.num_strings() {
+            let Some(file) = self
+                .client_lua_files
+                .string(index)
+                .and_then(|path| path.to_str().ok())
+                .and_then(|path| removed.get(path))
+            else {
+                continue;
+            };
+
+            self.client_lua_files.set_string_userdata(
+                index,
+                Sha256::digest(code_with_nul(&file.content)).as_slice(),
+            );
+        }
+    }
+
+    fn remove_files(&mut self, paths: &[String]) {
+        let removed: HashMap<String, PackedFile> = paths
+            .iter()
+            .filter_map(|path| self.files.remove_entry(path))
+            .collect();
+
+        self.restore_client_hashes(&removed);
+
+        if !removed.is_empty() {
+            self.content_changed = true;
+        }
+    }
+
+    /// Drops packed files deleted from disk or no longer sent to clients, virtual files are kept.
+    /// Returns sorted removed paths.
+    pub fn remove_missing_files(&mut self) -> Vec<String> {
+        let client_files = self.client_file_paths();
+
+        let mut removed: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(path, file)| {
+                !file.is_virtual && (!client_files.contains(*path) || !self.exists_on_disk(path))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        removed.sort();
+
+        self.remove_files(&removed);
+
+        removed
+    }
+
+    /// Drops packed files with given file name that were deleted from disk, used by auto-refresh
+    /// which only knows the name of changed file. Returns sorted removed paths.
+    pub fn remove_deleted_files(&mut self, filename: &str) -> Vec<String> {
+        let mut removed: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(path, file)| {
+                !file.is_virtual
+                    && path.rsplit('/').next() == Some(filename)
+                    && !self.exists_on_disk(path)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        removed.sort();
+
+        self.remove_files(&removed);
+
+        removed
+    }
+
+    pub fn edit_file(&mut self, path: &str, new_content: Vec<u8>) -> Result<(), EditFileError> {
         if let Some(packed_file) = self.files.get_mut(path) {
             self.content_changed = true;
 
@@ -201,134 +593,430 @@ impl PackUwUs {
         }
     }
 
-    fn pack(&self) -> Vec<u8> {
-        let mut buf = vec![];
+    pub fn benchmark_layouts(&self) -> Result<Vec<LayoutBenchmark>, BuildError> {
+        benchmark_layouts(&self.files, &self.options.compression)
+    }
+
+    pub fn set_options(&mut self, options: PackOptions) {
+        let old_compression = self.options.compression;
+
+        self.options = options;
 
-        for file in self.files.iter() {
-            let filepath = file.0.as_bytes();
-            let content = gmod_lzma::compress(file.1.content.as_bytes(), 9).unwrap();
+        if self.options.compression != old_compression {
+            println!("[PackUwUs] Compression options changed, next pack will be full");
 
-            buf.reserve(filepath.len() + size_of::<usize>() + 1 + content.len());
-            buf.write(filepath).unwrap();
-            buf.write(&[0]).unwrap();
-            buf.write(&content.len().to_le_bytes()).unwrap();
-            buf.write(content.as_slice()).unwrap();
+            self.invalidate_base();
         }
+    }
 
-        buf
+    /// Next dictionary encoded pack trains a new dictionary on current files and is full
+    pub fn retrain_dictionary(&mut self) {
+        self.dictionary = None;
+
+        self.invalidate_base();
     }
 
-    pub fn try_serve(&mut self) -> Result<Option<String>, TryServeError> {
-        if !self.content_changed {
-            return Ok(None);
+    /// Forces next pack to be full, e.g. when old chunks were built with other options
+    pub fn invalidate_base(&mut self) {
+        self.base = None;
+        self.content_changed = true;
+    }
+
+    fn write_served_file(&self, name: &str, data: &[u8]) -> Result<(), TryServeError> {
+        let out_path = CString::new(served_path(name)).unwrap();
+
+        // name is content hash, so only a cut off write leaves file of other size
+        if self.fs.exists(out_path.as_c_str(), Some(c"GAME"))
+            && self.fs.size(out_path.as_c_str(), Some(c"GAME")) as usize == data.len()
+        {
+            #[cfg(debug_assertions)]
+            println!("[PackUwUs] {} is up to date", out_path.to_string_lossy());
+
+            return Ok(());
         }
 
-        let hash = Uuid::new_v4().simple().to_string();
-        let out_path = format!("data/serve_packuwus/{}.bsp", hash);
+        println!("[PackUwUs] Writing {}", out_path.to_string_lossy());
+
+        let write_failed =
+            |err| TryServeError::WriteFileFailed(out_path.to_string_lossy().to_string(), err);
 
-        println!("[PackUwUs] Writing {}", out_path);
+        // engine doesn't create missing directories when opening file for writing
+        self.fs.create_dir_hierarchy(
+            CString::new(SERVE_DIRECTORY).unwrap().as_c_str(),
+            Some(c"GAME"),
+        );
+
+        // served directory is downloaded as is, clients must never get half written file
+        let tmp_path = CString::new(format!("{}.tmp", served_path(name))).unwrap();
 
         self.fs
-            .write_file(
-                CString::new(out_path.clone()).unwrap().as_c_str(),
-                Some(c"GAME"),
-                self.pack().as_slice(),
-            )
-            .or_else(|err| Err(TryServeError::WriteFileFailed(err)))?;
+            .write_file(tmp_path.as_c_str(), Some(c"GAME"), data)
+            .or_else(|err| Err(write_failed(err)))?;
+
+        self.fs.remove_file(out_path.as_c_str(), Some(c"GAME"));
+
+        if self
+            .fs
+            .rename(tmp_path.as_c_str(), out_path.as_c_str(), c"GAME")
+        {
+            Ok(())
+        } else {
+            Err(write_failed(WriteFileError::RenameFailed))
+        }
+    }
+
+    /// Removes served files no pack uses anymore. Recent ones are kept, clients
+    /// that started downloading previous pack still need them.
+    fn collect_served_garbage(&self, served_names: &[String]) -> Vec<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+
+        let listed: Vec<String> = self
+            .listed_served_files()
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+
+        let mut removed = vec![];
+
+        for entry in self.fs.find(
+            CString::new(format!("{}*", SERVE_DIRECTORY))
+                .unwrap()
+                .as_c_str(),
+            Some(c"GAME"),
+        ) {
+            let name = entry.name.to_string_lossy();
+            let is_served = served_names
+                .iter()
+                .any(|served_name| name == format!("{}.bsp", served_name));
+            let full_path = format!("{}{}", SERVE_DIRECTORY, name);
+
+            // clients still download files listed in downloadables
+            if entry.is_directory || is_served || listed.contains(&full_path) {
+                continue;
+            }
+
+            let Ok(path) = CString::new(full_path) else {
+                continue;
+            };
+
+            if now - self.fs.file_time(path.as_c_str(), Some(c"GAME")) < SERVED_FILE_LIFETIME {
+                continue;
+            }
+
+            self.fs.remove_file(path.as_c_str(), Some(c"GAME"));
+
+            removed.push(path.to_string_lossy().to_string());
+        }
+
+        removed.sort();
+
+        removed
+    }
+
+    /// Served files listed in downloadables, including ones of earlier packs and module loads
+    fn listed_served_files(&self) -> Vec<&CStr> {
+        (0..self.downloadables.num_strings())
+            .filter_map(|index| self.downloadables.string(index))
+            .filter(|str| str.to_bytes().starts_with(SERVE_DIRECTORY.as_bytes()))
+            .collect()
+    }
+
+    fn update_downloadables(&self, served_names: &[String]) {
+        let listed = self.listed_served_files();
+        let served_paths: Vec<CString> = served_names
+            .iter()
+            .map(|name| CString::new(served_path(name)).unwrap())
+            .collect();
+
+        // entries of files that aren't served anymore
+        let mut spare: Vec<&CStr> = listed
+            .iter()
+            .copied()
+            .filter(|old| !served_paths.iter().any(|path| path.as_c_str() == *old))
+            .collect();
+
+        for path in served_paths.iter() {
+            if listed.contains(&path.as_c_str()) {
+                continue;
+            }
+
+            // entries can't be removed and engine owns their strings, so only a name of the same
+            // length can be written in place
+            let reusable = spare
+                .iter()
+                .position(|old| old.count_bytes() == path.count_bytes());
+
+            if let Some(position) = reusable {
+                let old = spare.swap_remove(position);
+
+                println!(
+                    "[PackUwUs] Replacing {} with {} in downloadables",
+                    old.to_string_lossy(),
+                    path.to_string_lossy()
+                );
+
+                unsafe {
+                    copy_nonoverlapping(path.as_ptr(), old.as_ptr() as _, path.count_bytes() + 1);
+                };
+            } else {
+                let index = self.downloadables.add_string(true, path, None);
+
+                println!(
+                    "[PackUwUs] Added {} to downloadables (index: {})",
+                    path.to_string_lossy(),
+                    index
+                );
+            }
+        }
+
+        // left as is, their files are kept by garbage collection
+        for old in spare {
+            println!(
+                "[PackUwUs] Old served file {} stays in downloadables",
+                old.to_string_lossy()
+            );
+        }
+    }
+
+    /// Takes a snapshot of files to pack, `None` if nothing changed since last one.
+    /// Edits made after this are picked up by the next pack.
+    pub fn snapshot(&mut self) -> Option<PackSnapshot> {
+        if !self.content_changed {
+            return None;
+        }
+
+        self.content_changed = false;
+
+        Some(PackSnapshot {
+            files: self.files.clone(),
+            options: self.options,
+            base: self.base.clone(),
+            dictionary: self.dictionary.clone(),
+        })
+    }
+
+    /// Snapshot wasn't published, so its changes still have to be packed
+    pub fn pack_failed(&mut self) {
+        self.content_changed = true;
+    }
+
+    /// Writes built pack files and points clients to them
+    pub fn publish(&mut self, built: BuiltPack) -> Result<String, TryServeError> {
+        let packed_contents = code_with_nul(
+            self.packed_contents
+                .as_ref()
+                .ok_or_else(|| TryServeError::PackedContentsNotSet)?,
+        );
+
+        for chunk in built.chunks.iter() {
+            self.write_served_file(&chunk.entry.chunk_name, &chunk.data)?;
+        }
+
+        self.write_served_file(&built.manifest.name, &built.manifest.data)?;
 
         // Update lua file hashes
         println!("[PackUwUs] Updating lua file hashes");
 
+        let hash = Sha256::digest(&packed_contents);
+
         for index in 0..self.client_lua_files.num_strings() {
             let filepath = self.client_lua_files.string(index);
 
             if let Some(filepath) = filepath {
-                if self
-                    .files
-                    .contains_key(&filepath.to_string_lossy().to_string())
+                if built
+                    .paths
+                    .contains(&filepath.to_string_lossy().to_string())
                 {
-                    let mut hash = Sha256::new();
-
-                    hash.update(
-                        CString::new(
-                            self.packed_contents
-                                .as_ref()
-                                .ok_or_else(|| TryServeError::PackedContentsNotSet)?
-                                .as_str(),
-                        )
-                        .unwrap()
-                        .as_bytes_with_nul(),
-                    );
-
-                    let hash = hash.finalize();
-
                     self.client_lua_files
                         .set_string_userdata(index, hash.as_slice());
                 }
             }
         }
 
-        // Serve packed file
-        println!("[PackUwUs] Serving packed file");
+        // Serve packed files
+        let mut served_names = vec![built.manifest.name.clone()];
 
-        let out_path_c_str = CString::new(out_path).unwrap();
+        served_names.extend(built.base_names);
+        served_names.extend(
+            built
+                .chunks
+                .iter()
+                .map(|chunk| chunk.entry.chunk_name.clone()),
+        );
 
-        let mut downloadable_filepath = None;
+        println!("[PackUwUs] Serving {} files", served_names.len());
 
-        for index in 0..self.downloadables.num_strings() {
-            let str = self.downloadables.string(index).unwrap();
+        self.update_downloadables(&served_names);
 
-            if str.to_string_lossy().starts_with("data/serve_packuwus/") {
-                downloadable_filepath = Some((index, str));
-
-                println!(
-                    "[PackUwUs] Found old served packed file {}",
-                    str.to_string_lossy()
-                );
+        for path in self.collect_served_garbage(&served_names) {
+            println!("[PackUwUs] Removed unused {}", path);
+        }
 
-                break;
+        if let Some(new_base) = built.new_base {
+            if new_base.dictionary.is_some() {
+                self.dictionary = new_base.dictionary.clone();
             }
+
+            self.base = Some(Arc::new(new_base));
         }
 
-        if let Some(downloadable_filepath) = downloadable_filepath {
-            unsafe {
-                copy_nonoverlapping(
-                    out_path_c_str.as_ptr(),
-                    downloadable_filepath.1.as_ptr() as _,
-                    out_path_c_str.count_bytes() + 1,
-                );
-            };
-        } else {
-            let index = self.downloadables.add_string(true, &out_path_c_str, None);
+        println!("[PackUwUs] Internal pack done!");
+
+        Ok(built.manifest.name)
+    }
+
+    /// Packs synchronously on game thread
+    pub fn try_serve(&mut self) -> Result<String, TryServeError> {
+        let Some(snapshot) = self.snapshot() else {
+            return Err(TryServeError::NothingToPack);
+        };
+
+        let result = snapshot
+            .build(&Progress::default())
+            .and_then(|built| self.publish(built));
+
+        if result.is_err() {
+            self.pack_failed();
+        }
+
+        result
+    }
+}
+
+impl BuiltPack {
+    pub fn stats(&self) -> PackStats {
+        PackStats {
+            files: self.paths.len(),
+            chunks: self.chunks.len(),
+            bytes: self.manifest.data.len()
+                + self
+                    .chunks
+                    .iter()
+                    .map(|chunk| chunk.data.len())
+                    .sum::<usize>(),
+            delta: !self.base_names.is_empty(),
+        }
+    }
+}
+
+impl PackSnapshot {
+    fn virtual_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(_, file)| file.is_virtual)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        paths.sort();
+
+        paths
+    }
+
+    /// Compresses the pack, safe to run on any thread
+    pub fn build(&self, progress: &Progress) -> Result<BuiltPack, TryServeError> {
+        let virtual_paths = self.virtual_paths();
+
+        let delta = match &self.base {
+            Some(base) if self.options.delta => {
+                let delta = base
+                    .build_delta(&self.files, &self.options.compression, progress)
+                    .or_else(|err| Err(TryServeError::BuildFailed(err)))?;
+
+                // patch is too big to be worth it, rebase instead
+                if delta.files_count() * 2 <= self.files.len() {
+                    Some(delta)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let mut base_names = vec![];
+        let mut new_base = None;
+
+        let (manifest, chunks) = if let Some(delta) = delta {
+            let base = self.base.as_ref().unwrap();
 
             println!(
-                "[PackUwUs] Added new value to network string table (index: {})",
-                index
+                "[PackUwUs] Building delta pack on top of {} ({} files changed)",
+                base.manifest_name,
+                delta.files_count()
             );
-        }
 
-        self.content_changed = false;
+            let chunks: Vec<Chunk> = delta.chunk.into_iter().collect();
+            let manifest = build_manifest(
+                Some(&base.manifest_name),
+                &chunks.iter().map(|chunk| &chunk.entry).collect::<Vec<_>>(),
+                &delta.removed,
+                base.dictionary.as_ref(),
+                &virtual_paths,
+            );
 
-        println!("[PackUwUs] Internal pack done!");
+            base_names.push(base.manifest_name.clone());
+            base_names.extend(base.entries.iter().map(|entry| entry.chunk_name.clone()));
 
-        Ok(Some(hash))
+            (manifest, chunks)
+        } else {
+            let dictionary = self.options.compression.dictionary.then(|| {
+                self.dictionary
+                    .clone()
+                    .unwrap_or_else(|| train_dictionary(&self.files))
+            });
+
+            let chunks = build_chunks(
+                &self.files,
+                &self.options.compression,
+                dictionary.as_ref(),
+                progress,
+            )
+            .or_else(|err| Err(TryServeError::BuildFailed(err)))?;
+            let manifest = build_manifest(
+                None,
+                &chunks.iter().map(|chunk| &chunk.entry).collect::<Vec<_>>(),
+                &[],
+                dictionary.as_ref(),
+                &virtual_paths,
+            );
+
+            new_base = Some(BasePack::new(
+                manifest.name.clone(),
+                &chunks,
+                &self.files,
+                dictionary,
+            ));
+
+            (manifest, chunks)
+        };
+
+        Ok(BuiltPack {
+            manifest,
+            chunks,
+            base_names,
+            new_base,
+            paths: self.files.keys().cloned().collect(),
+        })
     }
 }
 
 impl PackUwUs {
     pub fn build_lua_download_packet(
         file_id: u16,
-        lua_code: &str,
+        lua_code: &[u8],
+        level: i32,
     ) -> Result<Vec<u8>, BuildLuaDownloadPacketError> {
-        let lua_code = CString::new(lua_code)
-            .or_else(|err| Err(BuildLuaDownloadPacketError::LuaCodeContainsNul(err)))?;
+        let lua_code = code_with_nul(lua_code);
 
-        let compressed_lua_code = gmod_lzma::compress(lua_code.as_bytes_with_nul(), 9)
+        let compressed_lua_code = gmod_lzma::compress(&lua_code, level)
             .or_else(|err| Err(BuildLuaDownloadPacketError::CompressFailed(err)))?;
 
         let lua_code_hash = {
             let mut hasher = Sha256::new();
-            hasher.update(lua_code.as_bytes_with_nul());
+            hasher.update(&lua_code);
             hasher.finalize().to_vec()
         };
 
@@ -358,28 +1046,25 @@ impl PackUwUs {
     }
 
     pub fn build_lua_autorefresh_packet(
-        filepath: &str,
-        lua_code: &str,
+        filepath: &CStr,
+        lua_code: &[u8],
+        level: i32,
     ) -> Result<Vec<u8>, BuildLuaAutoRefreshPacketError> {
-        let filepath = CString::new(filepath)
-            .or_else(|err| Err(BuildLuaAutoRefreshPacketError::FilepathContainsNul(err)))?;
-
-        let lua_code = CString::new(lua_code)
-            .or_else(|err| Err(BuildLuaAutoRefreshPacketError::LuaCodeContainsNul(err)))?;
+        let lua_code = code_with_nul(lua_code);
 
-        let compressed_lua_code = gmod_lzma::compress(lua_code.as_bytes_with_nul(), 9)
+        let compressed_lua_code = gmod_lzma::compress(&lua_code, level)
             .or_else(|err| Err(BuildLuaAutoRefreshPacketError::CompressFailed(err)))?;
 
         let lua_code_hash = {
             let mut hasher = Sha256::new();
-            hasher.update(lua_code.as_bytes_with_nul());
+            hasher.update(&lua_code);
             hasher.finalize().to_vec()
         };
 
         let mut new_data =
             vec![
                 0 as u8;
-                1 + filepath.as_bytes_with_nul().len() + 4 + 0x20 + compressed_lua_code.len() + 1
+                1 + filepath.to_bytes_with_nul().len() + 4 + 0x20 + compressed_lua_code.len() + 1
             ];
 
         new_data[0] = 1;
@@ -390,10 +1075,10 @@ impl PackUwUs {
             copy_nonoverlapping(
                 filepath.as_ptr(),
                 new_data_ptr as _,
-                filepath.as_bytes_with_nul().len(),
+                filepath.to_bytes_with_nul().len(),
             );
 
-            new_data_ptr = new_data_ptr.byte_offset(filepath.as_bytes_with_nul().len() as _);
+            new_data_ptr = new_data_ptr.byte_offset(filepath.to_bytes_with_nul().len() as _);
 
             (new_data_ptr as *mut u32).write_unaligned((compressed_lua_code.len() as u32) + 0x20);
 
@@ -413,3 +1098,373 @@ impl PackUwUs {
         Ok(new_data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ptr::null_mut, thread};
+
+    use super::*;
+    use crate::{
+        pack::tests::unpack_all,
+        sdk::{
+            filesystem::memory::MemoryFileSystem, networkstringtable::fake::FakeNetworkStringTable,
+        },
+        with_packuwus, PACKUWUS,
+    };
+
+    const PACKED_CONTENTS: &[u8] = b"return unpackMeUwU()()";
+
+    struct Harness {
+        fs: MemoryFileSystem,
+        downloadables: Box<FakeNetworkStringTable>,
+        client_lua_files: Box<FakeNetworkStringTable>,
+        packuwus: PackUwUs,
+    }
+
+    fn unix_now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    /// `files` exist on disk and are sent to clients
+    fn harness(files: &[(&str, &[u8])]) -> Harness {
+        let fs = MemoryFileSystem::default();
+        let downloadables = FakeNetworkStringTable::new();
+        let client_lua_files = FakeNetworkStringTable::new();
+
+        fs.set_now(unix_now());
+
+        for (path, content) in files {
+            fs.insert(path, content, 0);
+            client_lua_files.wrapped().add_string(
+                true,
+                CString::new(*path).unwrap().as_c_str(),
+                Some(Sha256::digest(code_with_nul(content)).as_slice()),
+            );
+        }
+
+        let mut packuwus = PackUwUs::new(
+            State(null_mut()),
+            Box::new(fs.clone()),
+            downloadables.wrapped(),
+            client_lua_files.wrapped(),
+        );
+
+        packuwus.packed_contents = Some(PACKED_CONTENTS.to_vec());
+
+        Harness {
+            fs,
+            downloadables,
+            client_lua_files,
+            packuwus,
+        }
+    }
+
+    fn served_files(fs: &MemoryFileSystem) -> Vec<String> {
+        fs.paths()
+            .into_iter()
+            .filter(|path| path.starts_with(SERVE_DIRECTORY))
+            .collect()
+    }
+
+    #[test]
+    fn removes_files_missing_on_disk_or_from_clients() {
+        let mut harness = harness(&[("lua/a.lua", b"a"), ("lua/b.lua", b"b")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+        packuwus.add_file("lua/b.lua", None).unwrap();
+        // on disk, but clients don't download it
+        harness.fs.insert("lua/server.lua", b"server", 0);
+        packuwus.add_file("lua/server.lua", None).unwrap();
+        packuwus.add_virtual_file("lua/virtual.lua", b"virtual".to_vec());
+
+        harness.fs.remove_file(c"lua/b.lua", Some(c"GAME"));
+        packuwus.content_changed = false;
+
+        assert_eq!(
+            packuwus.remove_missing_files(),
+            ["lua/b.lua", "lua/server.lua"]
+        );
+        assert!(packuwus.content_changed);
+        assert_eq!(packuwus.file_paths(), ["lua/a.lua", "lua/virtual.lua"]);
+
+        packuwus.content_changed = false;
+
+        assert!(packuwus.remove_missing_files().is_empty());
+        assert!(!packuwus.content_changed);
+    }
+
+    #[test]
+    fn restores_hashes_of_removed_files() {
+        let mut harness = harness(&[("lua/a.lua", b"a"), ("lua/b.lua", b"b")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+        packuwus.add_file("lua/b.lua", None).unwrap();
+        packuwus.try_serve().unwrap();
+
+        harness.fs.remove_file(c"lua/b.lua", Some(c"GAME"));
+
+        assert_eq!(packuwus.remove_missing_files(), ["lua/b.lua"]);
+
+        let stub_hash = Sha256::digest(code_with_nul(PACKED_CONTENTS)).to_vec();
+
+        assert_eq!(
+            harness.client_lua_files.userdata("lua/a.lua"),
+            Some(stub_hash)
+        );
+        assert_eq!(
+            harness.client_lua_files.userdata("lua/b.lua"),
+            Some(Sha256::digest(b"b\0").to_vec())
+        );
+    }
+
+    #[test]
+    fn restores_hash_of_file_removed_from_lua() {
+        let mut harness = harness(&[("lua/a.lua", b"a")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+        packuwus.try_serve().unwrap();
+        packuwus.remove_file("lua/a.lua").unwrap();
+
+        assert!(packuwus.content_changed);
+        assert_eq!(
+            harness.client_lua_files.userdata("lua/a.lua"),
+            Some(Sha256::digest(b"a\0").to_vec())
+        );
+        assert!(matches!(
+            packuwus.remove_file("lua/a.lua"),
+            Err(RemoveFileError::DontExist)
+        ));
+    }
+
+    #[test]
+    fn reads_added_files_from_disk() {
+        let mut harness = harness(&[("lua/a.lua", b"a\0b")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+
+        assert_eq!(packuwus.file("lua/a.lua").unwrap().content, b"a\0b");
+        assert!(matches!(
+            packuwus.add_file("lua/missing.lua", None),
+            Err(AddFileError::ReadFailed(_))
+        ));
+    }
+
+    #[test]
+    fn publishes_served_files() {
+        let mut harness = harness(&[("lua/a.lua", b"a"), ("lua/b.lua", b"b")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+        packuwus.add_file("lua/b.lua", None).unwrap();
+
+        let manifest_name = packuwus.try_serve().unwrap();
+        let served = served_files(&harness.fs);
+
+        assert!(served.contains(&served_path(&manifest_name)));
+        assert!(served.iter().all(|path| path.ends_with(".bsp")));
+
+        let mut downloadables = harness.downloadables.strings();
+
+        downloadables.sort();
+
+        assert_eq!(downloadables, served);
+
+        let stub_hash = Sha256::digest(code_with_nul(PACKED_CONTENTS)).to_vec();
+
+        assert_eq!(
+            harness.client_lua_files.userdata("lua/a.lua"),
+            Some(stub_hash)
+        );
+    }
+
+    #[test]
+    fn reuses_downloadables_of_same_length_only() {
+        let mut harness = harness(&[("lua/a.lua", b"a")]);
+        let short_path = served_path("short");
+
+        harness.fs.insert(&short_path, b"short", 0);
+        harness.downloadables.wrapped().add_string(
+            true,
+            &CString::new(short_path.clone()).unwrap(),
+            None,
+        );
+
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+
+        let manifest_path = served_path(&packuwus.try_serve().unwrap());
+        let first_pack = served_files(&harness.fs);
+        let downloadables = harness.downloadables.strings();
+
+        // shorter entry can't fit a new name, it's kept with its file and never aliased
+        assert_eq!(downloadables[0], short_path);
+        assert_eq!(
+            downloadables
+                .iter()
+                .filter(|path| **path == manifest_path)
+                .count(),
+            1
+        );
+        assert!(first_pack.contains(&short_path));
+        assert_eq!(downloadables.len(), first_pack.len());
+
+        packuwus
+            .edit_file("lua/a.lua", b"changed".to_vec())
+            .unwrap();
+
+        let new_manifest_path = served_path(&packuwus.try_serve().unwrap());
+        let downloadables = harness.downloadables.strings();
+
+        // unchanged chunks keep their entries, changed ones take over entries of the old ones
+        assert_eq!(downloadables.len(), first_pack.len());
+        assert!(downloadables.contains(&new_manifest_path));
+        assert!(!downloadables.contains(&manifest_path));
+        assert_eq!(downloadables[0], short_path);
+    }
+
+    #[test]
+    fn rewrites_cut_off_served_files() {
+        let mut harness = harness(&[("lua/a.lua", b"a")]);
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+
+        let manifest_path = served_path(&packuwus.try_serve().unwrap());
+        let manifest = harness.fs.content(&manifest_path).unwrap();
+
+        harness
+            .fs
+            .insert(&manifest_path, &manifest[..manifest.len() / 2], 0);
+
+        packuwus.invalidate_base();
+        packuwus.try_serve().unwrap();
+
+        assert_eq!(harness.fs.content(&manifest_path).unwrap(), manifest);
+    }
+
+    #[test]
+    fn collects_old_unused_served_files() {
+        let mut harness = harness(&[("lua/a.lua", b"a")]);
+        let old_path = served_path("old");
+        let recent_path = served_path("recent");
+
+        harness.fs.insert(&old_path, b"old", 0);
+        harness.fs.insert(&recent_path, b"recent", unix_now());
+
+        let packuwus = &mut harness.packuwus;
+
+        packuwus.add_file("lua/a.lua", None).unwrap();
+        packuwus.try_serve().unwrap();
+
+        let first_pack = served_files(&harness.fs);
+
+        assert!(!first_pack.contains(&old_path));
+        assert!(first_pack.contains(&recent_path));
+
+        packuwus
+            .edit_file("lua/a.lua", b"changed".to_vec())
+            .unwrap();
+        packuwus.try_serve().unwrap();
+
+        // previous pack is recent, so it's kept for clients still downloading it
+        let second_pack = served_files(&harness.fs);
+
+        assert!(first_pack.iter().all(|path| second_pack.contains(path)));
+        assert!(second_pack.len() > first_pack.len());
+    }
+
+    fn live_files() -> HashMap<String, Vec<u8>> {
+        with_packuwus(|packuwus| {
+            packuwus
+                .files
+                .iter()
+                .map(|(path, file)| (path.clone(), file.content.clone()))
+                .collect()
+        })
+    }
+
+    // only test using global state, others own their PackUwUs
+    #[test]
+    fn packs_snapshots_during_concurrent_edits() {
+        let Harness {
+            fs,
+            downloadables: _downloadables,
+            client_lua_files: _client_lua_files,
+            packuwus,
+        } = harness(&[]);
+
+        *PACKUWUS.lock().unwrap() = Some(packuwus);
+
+        for i in 0..20 {
+            with_packuwus(|packuwus| {
+                packuwus
+                    .add_file(&format!("lua/file_{}.lua", i), Some(vec![b'0'; i]))
+                    .unwrap()
+            });
+        }
+
+        let mut next_file = 20;
+
+        for round in 0..10 {
+            let snapshot = with_packuwus(|packuwus| packuwus.snapshot()).unwrap();
+            let expected = live_files();
+
+            let worker = thread::spawn(move || snapshot.build(&Progress::default()));
+
+            // edits land while the worker compresses the snapshot
+            while !worker.is_finished() || next_file % 5 != 0 {
+                let path = format!("lua/file_{}.lua", next_file);
+
+                with_packuwus(|packuwus| {
+                    packuwus
+                        .add_file(&path, Some(format!("{} {}", round, next_file).into_bytes()))
+                        .unwrap();
+
+                    let edited = format!("lua/file_{}.lua", next_file - 3);
+
+                    if packuwus.is_packed(&edited) {
+                        packuwus
+                            .edit_file(&edited, format!("edited {}", round).into_bytes())
+                            .unwrap();
+                    }
+
+                    packuwus
+                        .remove_file(&format!("lua/file_{}.lua", next_file - 10))
+                        .ok();
+
+                    packuwus.packed_contents =
+                        Some(format!("return unpackMeUwU({})()", round).into_bytes());
+                });
+
+                next_file += 1;
+            }
+
+            let built = worker.join().unwrap().unwrap();
+
+            // pack has the files as they were when snapshot was taken, none of later edits
+            assert_eq!(built.paths, expected.keys().cloned().collect());
+            assert_eq!(unpack_all(&built.chunks), expected);
+
+            with_packuwus(|packuwus| packuwus.publish(built)).unwrap();
+
+            assert!(fs.paths().iter().all(|path| !path.ends_with(".tmp")));
+        }
+
+        // edits made during the last build are picked up by the next pack
+        let snapshot = with_packuwus(|packuwus| packuwus.snapshot()).unwrap();
+        let built = snapshot.build(&Progress::default()).unwrap();
+
+        assert_eq!(unpack_all(&built.chunks), live_files());
+        assert!(with_packuwus(|packuwus| packuwus.snapshot()).is_none());
+
+        *PACKUWUS.lock().unwrap() = None;
+    }
+}
@@ -1,11 +1,291 @@
-use std::ffi::{c_uint, c_void};
+use std::{
+    ffi::{c_uint, c_void},
+    ptr::copy_nonoverlapping,
+    slice,
+};
 
+extern "C" {
+    // Bootil allocates buffer data with libc allocator and frees it in destructor,
+    // so it must be grown the same way
+    fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+}
+
+/// `Bootil::AutoBuffer`, growable buffer with read/write position. Reads and writes
+/// behave like Bootil ones: they start at `pos` and move it forward. Engine buffers must
+/// only be changed on game thread.
 #[repr(C)]
 #[derive(Debug)]
 pub struct AutoBuffer {
-    pub _unk_1: *const c_void,
-    pub data: *const c_void,
-    pub _unk_2: *const c_void,
-    pub pos: c_uint,
-    pub written: c_uint,
+    _vtable: *const c_void,
+    data: *mut c_void,
+    /// Allocated size
+    size: c_uint,
+    pos: c_uint,
+    /// Bytes written, the rest of allocation is garbage
+    written: c_uint,
+}
+
+// offsets of 32-bit srcds are in comments
+const _: () = {
+    use std::mem::{offset_of, size_of};
+
+    const PTR: usize = size_of::<usize>();
+
+    assert!(offset_of!(AutoBuffer, _vtable) == 0);
+    assert!(offset_of!(AutoBuffer, data) == PTR); // 0x04
+    assert!(offset_of!(AutoBuffer, size) == 2 * PTR); // 0x08
+    assert!(offset_of!(AutoBuffer, pos) == 2 * PTR + 4); // 0x0c
+    assert!(offset_of!(AutoBuffer, written) == 2 * PTR + 8); // 0x10
+    assert!(size_of::<AutoBuffer>() == (2 * PTR + 12).next_multiple_of(PTR)); // 0x14
+};
+
+impl AutoBuffer {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn pos(&self) -> u32 {
+        self.pos
+    }
+
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Written bytes, empty if buffer was never allocated
+    pub fn data(&self) -> &[u8] {
+        if self.data.is_null() {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.data as *const u8, self.written as _) }
+    }
+
+    /// Position is clamped to written bytes
+    pub fn set_pos(&mut self, pos: u32) {
+        self.pos = pos.min(self.written);
+    }
+
+    /// Reads written bytes from current position, returns count of read bytes
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let available = self.data().get(self.pos as usize..).unwrap_or_default();
+        let len = available.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&available[..len]);
+
+        self.pos += len as c_uint;
+
+        len
+    }
+
+    fn ensure_capacity(&mut self, size: usize) -> bool {
+        if size <= self.size as usize {
+            return true;
+        }
+
+        let data = unsafe { realloc(self.data, size) };
+
+        if data.is_null() {
+            return false;
+        }
+
+        self.data = data;
+        self.size = size as _;
+
+        true
+    }
+
+    /// Writes at current position, growing buffer if needed. Returns false if nothing was
+    /// written: position is past written bytes, size overflows or buffer couldn't be grown.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        // gap between written bytes and position would be garbage
+        if self.pos > self.written {
+            return false;
+        }
+
+        let Some(end) = (self.pos as usize)
+            .checked_add(data.len())
+            .filter(|end| *end <= c_uint::MAX as usize)
+        else {
+            return false;
+        };
+
+        if !self.ensure_capacity(end) {
+            return false;
+        }
+
+        unsafe {
+            copy_nonoverlapping(
+                data.as_ptr(),
+                (self.data as *mut u8).add(self.pos as _),
+                data.len(),
+            )
+        };
+
+        self.pos = end as _;
+        self.written = self.written.max(self.pos);
+
+        true
+    }
+
+    /// Forgets written bytes, allocation is kept
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.written = 0;
+    }
+
+    /// Replaces buffer contents with `data`, nothing is changed if it returns false
+    pub fn replace(&mut self, data: &[u8]) -> bool {
+        if data.len() > c_uint::MAX as usize || !self.ensure_capacity(data.len()) {
+            return false;
+        }
+
+        self.clear();
+
+        self.write(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::{size_of, size_of_val},
+        ptr::null,
+    };
+
+    use super::*;
+
+    /// Memory image of engine AutoBuffer, built word by word instead of through the struct
+    fn buffer_image(data: *const u8, size: u32, pos: u32, written: u32) -> Vec<usize> {
+        let mut words = vec![0xdead, data as usize];
+        let mut ints: Vec<u8> = [size, pos, written]
+            .iter()
+            .flat_map(|int| int.to_ne_bytes())
+            .collect();
+
+        ints.resize(ints.len().next_multiple_of(size_of::<usize>()), 0);
+
+        for word in ints.chunks(size_of::<usize>()) {
+            words.push(usize::from_ne_bytes(word.try_into().unwrap()));
+        }
+
+        words
+    }
+
+    fn buffer(image: &[usize]) -> &AutoBuffer {
+        assert_eq!(size_of_val(image), size_of::<AutoBuffer>());
+
+        unsafe { &*(image.as_ptr() as *const AutoBuffer) }
+    }
+
+    #[test]
+    fn reads_engine_layout() {
+        let allocation = b"compressed\0garbage";
+        let image = buffer_image(allocation.as_ptr(), allocation.len() as _, 3, 11);
+        let buffer = buffer(&image);
+
+        assert_eq!(buffer.size(), allocation.len() as u32);
+        assert_eq!(buffer.pos(), 3);
+        assert_eq!(buffer.written(), 11);
+        // allocation past written bytes is never exposed
+        assert_eq!(buffer.data(), b"compressed\0");
+    }
+
+    extern "C" {
+        fn free(ptr: *mut c_void);
+    }
+
+    /// Empty buffer owned by the test, allocated by `realloc` like Bootil does
+    struct OwnedBuffer(AutoBuffer);
+
+    impl OwnedBuffer {
+        fn new() -> OwnedBuffer {
+            OwnedBuffer(AutoBuffer {
+                _vtable: null(),
+                data: null::<c_void>() as _,
+                size: 0,
+                pos: 0,
+                written: 0,
+            })
+        }
+    }
+
+    impl Drop for OwnedBuffer {
+        fn drop(&mut self) {
+            unsafe { free(self.0.data) };
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_back() {
+        let mut owned = OwnedBuffer::new();
+        let buffer = &mut owned.0;
+
+        assert!(buffer.write(b"hello"));
+        assert!(buffer.write(b" world"));
+        assert_eq!(buffer.data(), b"hello world");
+        assert_eq!(buffer.pos(), 11);
+        assert!(buffer.size() >= 11);
+
+        // overwrite in the middle keeps the rest
+        buffer.set_pos(6);
+        assert!(buffer.write(b"W"));
+        assert_eq!(buffer.data(), b"hello World");
+
+        let mut read = [0; 4];
+
+        buffer.set_pos(100);
+        assert_eq!(buffer.pos(), 11);
+        assert_eq!(buffer.read(&mut read), 0);
+
+        buffer.set_pos(7);
+        assert_eq!(buffer.read(&mut read), 4);
+        assert_eq!(&read, b"orld");
+    }
+
+    #[test]
+    fn replaces_and_clears() {
+        let mut owned = OwnedBuffer::new();
+        let buffer = &mut owned.0;
+
+        assert!(buffer.replace(b"long original content"));
+        assert!(buffer.replace(b"short"));
+        assert_eq!(buffer.data(), b"short");
+        assert_eq!(buffer.pos(), 5);
+
+        let size = buffer.size();
+
+        buffer.clear();
+        assert!(buffer.data().is_empty());
+        assert_eq!(buffer.size(), size);
+    }
+
+    #[test]
+    fn position_past_written_bytes_is_bounded() {
+        let mut owned = OwnedBuffer::new();
+        let buffer = &mut owned.0;
+
+        assert!(buffer.write(b"abc"));
+
+        // engine state we don't control
+        buffer.pos = 10;
+
+        let mut read = [0; 4];
+
+        assert_eq!(buffer.read(&mut read), 0);
+        assert!(!buffer.write(b"d"));
+        assert_eq!(buffer.data(), b"abc");
+    }
+
+    #[test]
+    fn unallocated_buffer_is_empty() {
+        let image = buffer_image(null(), 0, 0, 0);
+
+        assert!(buffer(&image).data().is_empty());
+
+        let allocation = [0u8; 16];
+        let image = buffer_image(allocation.as_ptr(), 16, 0, 0);
+
+        assert!(buffer(&image).data().is_empty());
+    }
 }
@@ -1,24 +1,60 @@
 use std::{
-    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    ffi::{c_char, c_int, c_long, c_uint, c_void, CStr, CString, OsStr},
+    fmt::Debug,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
     ptr::null,
 };
 
 pub type FileHandle = *const c_void;
+pub type FileFindHandle = c_int;
 
 pub const INVALID_FILE_HANDLE: FileHandle = null();
 
+/// IAppSystem and IFileSystem part of the interface, indices are taken from SDK 2013
 #[repr(C)]
 #[derive(Debug)]
 pub struct FileSystemVTable0 {
-    _pad_1: [usize; 0x10],
+    _pad_1: [usize; 13],
+    /// Returns length of all paths with \0, even if it didn't fit into the buffer
+    pub get_search_path: unsafe extern "C" fn(
+        *const *const FileSystemVTable0,
+        *const c_char,
+        bool,
+        *mut c_char,
+        c_int,
+    ) -> c_int,
+    _pad_2: [usize; 1],
+    pub remove_file:
+        unsafe extern "C" fn(*const *const FileSystemVTable0, *const c_char, *const c_char),
     pub rename: unsafe extern "C" fn(
         *const *const FileSystemVTable0,
         *const c_char,
         *const c_char,
         *const c_char,
     ) -> bool,
+    pub create_dir_hierarchy:
+        unsafe extern "C" fn(*const *const FileSystemVTable0, *const c_char, *const c_char),
+    _pad_3: [usize; 9],
+    pub find_first: unsafe extern "C" fn(
+        *const *const FileSystemVTable0,
+        *const c_char,
+        *mut FileFindHandle,
+    ) -> *const c_char,
+    pub find_next:
+        unsafe extern "C" fn(*const *const FileSystemVTable0, FileFindHandle) -> *const c_char,
+    pub find_is_directory:
+        unsafe extern "C" fn(*const *const FileSystemVTable0, FileFindHandle) -> bool,
+    pub find_close: unsafe extern "C" fn(*const *const FileSystemVTable0, FileFindHandle),
+    pub find_first_ex: unsafe extern "C" fn(
+        *const *const FileSystemVTable0,
+        *const c_char,
+        *const c_char,
+        *mut FileFindHandle,
+    ) -> *const c_char,
 }
 
+/// IBaseFileSystem part of the interface
 #[repr(C)]
 #[derive(Debug)]
 pub struct FileSystemVTable1 {
@@ -43,11 +79,39 @@ pub struct FileSystemVTable1 {
     pub close: unsafe extern "C" fn(*const *const FileSystemVTable1, FileHandle) -> FileHandle,
     _pad_2: [usize; 2],
     pub size: unsafe extern "C" fn(*const *const FileSystemVTable1, FileHandle) -> c_uint,
-    _pad_3: [usize; 2],
+    pub size_by_name: unsafe extern "C" fn(
+        *const *const FileSystemVTable1,
+        *const c_char,
+        *const c_char,
+    ) -> c_uint,
+    _pad_3: [usize; 1],
     pub exists:
         unsafe extern "C" fn(*const *const FileSystemVTable1, *const c_char, *const c_char) -> bool,
+    _pad_4: [usize; 2],
+    pub get_file_time: unsafe extern "C" fn(
+        *const *const FileSystemVTable1,
+        *const c_char,
+        *const c_char,
+    ) -> c_long,
 }
 
+#[cfg(target_pointer_width = "32")]
+const _: () = {
+    use std::mem::offset_of;
+
+    assert!(offset_of!(FileSystemVTable0, get_search_path) == 13 * 4);
+    assert!(offset_of!(FileSystemVTable0, remove_file) == 15 * 4);
+    assert!(offset_of!(FileSystemVTable0, rename) == 16 * 4);
+    assert!(offset_of!(FileSystemVTable0, create_dir_hierarchy) == 17 * 4);
+    assert!(offset_of!(FileSystemVTable0, find_first) == 27 * 4);
+    assert!(offset_of!(FileSystemVTable0, find_first_ex) == 31 * 4);
+
+    assert!(offset_of!(FileSystemVTable1, size) == 6 * 4);
+    assert!(offset_of!(FileSystemVTable1, size_by_name) == 7 * 4);
+    assert!(offset_of!(FileSystemVTable1, exists) == 9 * 4);
+    assert!(offset_of!(FileSystemVTable1, get_file_time) == 12 * 4);
+};
+
 #[derive(thiserror::Error, Debug)]
 pub enum ReadFileError {
     #[error("Failed to open file")]
@@ -58,36 +122,97 @@ pub enum ReadFileError {
 pub enum WriteFileError {
     #[error("Failed to open file")]
     OpenFailed,
+    #[error("Failed to move written file in place")]
+    RenameFailed,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SearchPathError {
+    #[error("Search path changed while it was read")]
+    Changed,
+}
+
+/// Splits `;` separated search path list, directories end with `/`
+fn split_search_path(paths: &[u8]) -> Vec<PathBuf> {
+    paths
+        .split(|byte| *byte == b';')
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct FindEntry {
+    /// File name without directory
+    pub name: CString,
+    pub is_directory: bool,
+}
+
+/// Engine filesystem operations PackUwUs uses. `path_id` is a search path like `GAME` or `DATA`,
+/// `None` searches everywhere.
+pub trait FileSystem: Debug {
+    fn read_file(&self, filepath: &CStr, path_id: Option<&CStr>) -> Result<Vec<u8>, ReadFileError>;
+
+    fn write_file(
+        &self,
+        filepath: &CStr,
+        path_id: Option<&CStr>,
+        content: &[u8],
+    ) -> Result<(), WriteFileError>;
+
+    fn exists(&self, filepath: &CStr, path_id: Option<&CStr>) -> bool;
+
+    fn rename(&self, from: &CStr, to: &CStr, path_id: &CStr) -> bool;
+
+    fn remove_file(&self, filepath: &CStr, path_id: Option<&CStr>);
+
+    /// Creates directory with all its parents
+    fn create_dir_hierarchy(&self, path: &CStr, path_id: Option<&CStr>);
+
+    /// Size in bytes, 0 if file doesn't exist
+    fn size(&self, filepath: &CStr, path_id: Option<&CStr>) -> u32;
+
+    /// Modification time as unix timestamp, 0 if file doesn't exist
+    fn file_time(&self, filepath: &CStr, path_id: Option<&CStr>) -> i64;
+
+    /// Lists entries matching `wildcard`, e.g. `lua/autorun/*`. `.` and `..` are skipped.
+    fn find(&self, wildcard: &CStr, path_id: Option<&CStr>) -> Vec<FindEntry>;
+
+    /// Absolute directories `path_id` consists of, in search order. Pack files like VPKs are
+    /// listed too if `add_pack_files` is set. Unknown path id has no paths.
+    fn get_search_path(
+        &self,
+        path_id: &CStr,
+        add_pack_files: bool,
+    ) -> Result<Vec<PathBuf>, SearchPathError>;
 }
 
 #[repr(C)]
 #[derive(Debug)]
-pub struct FileSystem {
+pub struct RawFileSystem {
     pub vtable_0: *const FileSystemVTable0,
     pub vtable_1: *const FileSystemVTable1,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct WrappedFileSystem(pub *const FileSystem);
+pub struct WrappedFileSystem(pub *const RawFileSystem);
 
-impl WrappedFileSystem {
-    pub fn read_file(
-        &self,
-        filepath: &CStr,
-        path_id: Option<&CStr>,
-    ) -> Result<Vec<u8>, ReadFileError> {
-        let path_id = if let Some(path_id) = path_id {
-            path_id.as_ptr()
-        } else {
-            null()
-        };
+fn path_id_ptr(path_id: Option<&CStr>) -> *const c_char {
+    if let Some(path_id) = path_id {
+        path_id.as_ptr()
+    } else {
+        null()
+    }
+}
 
+impl FileSystem for WrappedFileSystem {
+    fn read_file(&self, filepath: &CStr, path_id: Option<&CStr>) -> Result<Vec<u8>, ReadFileError> {
         let handle = unsafe {
             ((*(*self.0).vtable_1).open)(
                 &(*self.0).vtable_1,
                 filepath.as_ptr(),
                 c"rb".as_ptr(),
-                path_id,
+                path_id_ptr(path_id),
             )
         };
 
@@ -113,24 +238,18 @@ impl WrappedFileSystem {
         Ok(buf)
     }
 
-    pub fn write_file(
+    fn write_file(
         &self,
         filepath: &CStr,
         path_id: Option<&CStr>,
         content: &[u8],
     ) -> Result<(), WriteFileError> {
-        let path_id = if let Some(path_id) = path_id {
-            path_id.as_ptr()
-        } else {
-            null()
-        };
-
         let handle = unsafe {
             ((*(*self.0).vtable_1).open)(
                 &(*self.0).vtable_1,
                 filepath.as_ptr(),
                 c"wb".as_ptr(),
-                path_id,
+                path_id_ptr(path_id),
             )
         };
 
@@ -152,17 +271,17 @@ impl WrappedFileSystem {
         Ok(())
     }
 
-    pub fn exists(&self, filepath: &CStr, path_id: Option<&CStr>) -> bool {
-        let path_id = if let Some(path_id) = path_id {
-            path_id.as_ptr()
-        } else {
-            null()
-        };
-
-        unsafe { ((*(*self.0).vtable_1).exists)(&(*self.0).vtable_1, filepath.as_ptr(), path_id) }
+    fn exists(&self, filepath: &CStr, path_id: Option<&CStr>) -> bool {
+        unsafe {
+            ((*(*self.0).vtable_1).exists)(
+                &(*self.0).vtable_1,
+                filepath.as_ptr(),
+                path_id_ptr(path_id),
+            )
+        }
     }
 
-    pub fn rename(&self, from: &CStr, to: &CStr, path_id: &CStr) -> bool {
+    fn rename(&self, from: &CStr, to: &CStr, path_id: &CStr) -> bool {
         unsafe {
             ((*(*self.0).vtable_0).rename)(
                 &(*self.0).vtable_0,
@@ -172,4 +291,342 @@ impl WrappedFileSystem {
             )
         }
     }
+
+    fn remove_file(&self, filepath: &CStr, path_id: Option<&CStr>) {
+        unsafe {
+            ((*(*self.0).vtable_0).remove_file)(
+                &(*self.0).vtable_0,
+                filepath.as_ptr(),
+                path_id_ptr(path_id),
+            )
+        }
+    }
+
+    fn create_dir_hierarchy(&self, path: &CStr, path_id: Option<&CStr>) {
+        unsafe {
+            ((*(*self.0).vtable_0).create_dir_hierarchy)(
+                &(*self.0).vtable_0,
+                path.as_ptr(),
+                path_id_ptr(path_id),
+            )
+        }
+    }
+
+    fn size(&self, filepath: &CStr, path_id: Option<&CStr>) -> u32 {
+        unsafe {
+            ((*(*self.0).vtable_1).size_by_name)(
+                &(*self.0).vtable_1,
+                filepath.as_ptr(),
+                path_id_ptr(path_id),
+            )
+        }
+    }
+
+    fn file_time(&self, filepath: &CStr, path_id: Option<&CStr>) -> i64 {
+        unsafe {
+            ((*(*self.0).vtable_1).get_file_time)(
+                &(*self.0).vtable_1,
+                filepath.as_ptr(),
+                path_id_ptr(path_id),
+            ) as _
+        }
+    }
+
+    fn find(&self, wildcard: &CStr, path_id: Option<&CStr>) -> Vec<FindEntry> {
+        let mut entries = vec![];
+        let mut handle: FileFindHandle = 0;
+
+        let mut name = unsafe {
+            ((*(*self.0).vtable_0).find_first_ex)(
+                &(*self.0).vtable_0,
+                wildcard.as_ptr(),
+                path_id_ptr(path_id),
+                &mut handle,
+            )
+        };
+
+        // handle is only valid if something was found
+        if name.is_null() {
+            return entries;
+        }
+
+        while !name.is_null() {
+            let name_c_str = unsafe { CStr::from_ptr(name) };
+
+            if name_c_str != c"." && name_c_str != c".." {
+                entries.push(FindEntry {
+                    name: name_c_str.to_owned(),
+                    is_directory: unsafe {
+                        ((*(*self.0).vtable_0).find_is_directory)(&(*self.0).vtable_0, handle)
+                    },
+                });
+            }
+
+            name = unsafe { ((*(*self.0).vtable_0).find_next)(&(*self.0).vtable_0, handle) };
+        }
+
+        unsafe { ((*(*self.0).vtable_0).find_close)(&(*self.0).vtable_0, handle) };
+
+        entries
+    }
+
+    fn get_search_path(
+        &self,
+        path_id: &CStr,
+        add_pack_files: bool,
+    ) -> Result<Vec<PathBuf>, SearchPathError> {
+        let get = |buf: &mut [u8]| unsafe {
+            ((*(*self.0).vtable_0).get_search_path)(
+                &(*self.0).vtable_0,
+                path_id.as_ptr(),
+                add_pack_files,
+                buf.as_mut_ptr() as _,
+                buf.len() as _,
+            )
+        };
+
+        let mut buf = vec![0; 1024];
+        let len = get(&mut buf) as usize;
+
+        // engine cuts paths off to buffer size, so it's asked again with the size it needs
+        if len > buf.len() {
+            buf.resize(len, 0);
+
+            if get(&mut buf) as usize > buf.len() {
+                return Err(SearchPathError::Changed);
+            }
+        }
+
+        Ok(split_search_path(
+            CStr::from_bytes_until_nul(&buf)
+                .map(|paths| paths.to_bytes())
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+/// In-memory filesystem for tests. Files ignore search paths, directories exist implicitly
+/// when a file is inside them. Clones share files.
+#[cfg(test)]
+pub(crate) mod memory {
+    use std::{
+        collections::BTreeMap,
+        ffi::{CStr, CString},
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{FileSystem, FindEntry, ReadFileError, SearchPathError, WriteFileError};
+
+    #[derive(Debug, Clone)]
+    struct MemoryFile {
+        content: Vec<u8>,
+        time: i64,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct MemoryFileSystem {
+        files: Arc<Mutex<BTreeMap<String, MemoryFile>>>,
+        /// Modification time of written files
+        now: Arc<Mutex<i64>>,
+        search_paths: Arc<Mutex<BTreeMap<String, Vec<PathBuf>>>>,
+    }
+
+    fn key(path: &CStr) -> String {
+        path.to_string_lossy().to_string()
+    }
+
+    // wildcard is `*` in the last path component, e.g. `dir/*.bsp`
+    fn matches_wildcard(name: &str, wildcard: &str) -> bool {
+        match wildcard.split_once('*') {
+            Some((prefix, suffix)) => {
+                name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix)
+                    && name.ends_with(suffix)
+            }
+            None => name == wildcard,
+        }
+    }
+
+    impl MemoryFileSystem {
+        pub fn insert(&self, path: &str, content: &[u8], time: i64) {
+            self.files.lock().unwrap().insert(
+                path.to_string(),
+                MemoryFile {
+                    content: content.to_vec(),
+                    time,
+                },
+            );
+        }
+
+        pub fn set_search_path(&self, path_id: &str, paths: &[&str]) {
+            self.search_paths.lock().unwrap().insert(
+                path_id.to_string(),
+                paths.iter().map(PathBuf::from).collect(),
+            );
+        }
+
+        pub fn set_now(&self, now: i64) {
+            *self.now.lock().unwrap() = now;
+        }
+
+        pub fn content(&self, path: &str) -> Option<Vec<u8>> {
+            self.files
+                .lock()
+                .unwrap()
+                .get(path)
+                .map(|file| file.content.clone())
+        }
+
+        /// Sorted paths of every file
+        pub fn paths(&self) -> Vec<String> {
+            self.files.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    impl FileSystem for MemoryFileSystem {
+        fn read_file(
+            &self,
+            filepath: &CStr,
+            _path_id: Option<&CStr>,
+        ) -> Result<Vec<u8>, ReadFileError> {
+            self.content(&key(filepath))
+                .ok_or(ReadFileError::OpenFailed)
+        }
+
+        fn write_file(
+            &self,
+            filepath: &CStr,
+            _path_id: Option<&CStr>,
+            content: &[u8],
+        ) -> Result<(), WriteFileError> {
+            self.insert(&key(filepath), content, *self.now.lock().unwrap());
+
+            Ok(())
+        }
+
+        fn exists(&self, filepath: &CStr, _path_id: Option<&CStr>) -> bool {
+            self.files.lock().unwrap().contains_key(&key(filepath))
+        }
+
+        fn rename(&self, from: &CStr, to: &CStr, _path_id: &CStr) -> bool {
+            let mut files = self.files.lock().unwrap();
+
+            match files.remove(&key(from)) {
+                Some(file) => {
+                    files.insert(key(to), file);
+
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn remove_file(&self, filepath: &CStr, _path_id: Option<&CStr>) {
+            self.files.lock().unwrap().remove(&key(filepath));
+        }
+
+        fn create_dir_hierarchy(&self, _path: &CStr, _path_id: Option<&CStr>) {}
+
+        fn size(&self, filepath: &CStr, _path_id: Option<&CStr>) -> u32 {
+            self.content(&key(filepath))
+                .map(|content| content.len() as _)
+                .unwrap_or(0)
+        }
+
+        fn file_time(&self, filepath: &CStr, _path_id: Option<&CStr>) -> i64 {
+            self.files
+                .lock()
+                .unwrap()
+                .get(&key(filepath))
+                .map(|file| file.time)
+                .unwrap_or(0)
+        }
+
+        fn find(&self, wildcard: &CStr, _path_id: Option<&CStr>) -> Vec<FindEntry> {
+            let wildcard = key(wildcard);
+            let (directory, name_wildcard) = wildcard.rsplit_once('/').unwrap_or(("", &wildcard));
+            let prefix = if directory.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", directory)
+            };
+
+            let mut entries: Vec<FindEntry> = vec![];
+
+            for path in self.files.lock().unwrap().keys() {
+                let Some(rest) = path.strip_prefix(&prefix) else {
+                    continue;
+                };
+
+                let (name, is_directory) = match rest.split_once('/') {
+                    Some((name, _)) => (name, true),
+                    None => (rest, false),
+                };
+
+                if matches_wildcard(name, name_wildcard)
+                    && !entries
+                        .iter()
+                        .any(|entry| entry.name.to_bytes() == name.as_bytes())
+                {
+                    entries.push(FindEntry {
+                        name: CString::new(name).unwrap(),
+                        is_directory,
+                    });
+                }
+            }
+
+            entries
+        }
+
+        fn get_search_path(
+            &self,
+            path_id: &CStr,
+            _add_pack_files: bool,
+        ) -> Result<Vec<PathBuf>, SearchPathError> {
+            Ok(self
+                .search_paths
+                .lock()
+                .unwrap()
+                .get(&key(path_id))
+                .cloned()
+                .unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    use super::{memory::MemoryFileSystem, split_search_path, FileSystem};
+
+    #[test]
+    fn splits_search_path() {
+        assert_eq!(
+            split_search_path(b"/srv/garrysmod/;/srv/garrysmod/garrysmod.vpk;"),
+            [
+                PathBuf::from("/srv/garrysmod/"),
+                PathBuf::from("/srv/garrysmod/garrysmod.vpk")
+            ]
+        );
+        assert!(split_search_path(b"").is_empty());
+        assert_eq!(
+            split_search_path(b"/srv/\xff/"),
+            [PathBuf::from(OsStr::from_bytes(b"/srv/\xff/"))]
+        );
+    }
+
+    #[test]
+    fn memory_search_paths() {
+        let fs = MemoryFileSystem::default();
+
+        fs.set_search_path("DATA", &["/srv/garrysmod/data/"]);
+
+        assert_eq!(
+            fs.get_search_path(c"DATA", false).unwrap(),
+            [PathBuf::from("/srv/garrysmod/data/")]
+        );
+        assert!(fs.get_search_path(c"GAME", false).unwrap().is_empty());
+    }
 }
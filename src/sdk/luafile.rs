@@ -1,17 +1,28 @@
 use std::{
-    ffi::{c_void, CStr},
+    ffi::{c_char, c_uint, CStr},
     fmt::Display,
+    slice,
 };
 
 use super::bootil::buffer::AutoBuffer;
 
+/// gcc COW std::string, it's a pointer to data with [`CowStringRep`] stored right before it
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
-pub struct LuaFileString(*const c_void);
+pub struct LuaFileString(*const c_char);
+
+/// `std::basic_string::_Rep` of pre-C++11 libstdc++ ABI srcds is built with
+#[repr(C)]
+#[derive(Debug)]
+struct CowStringRep {
+    length: usize,
+    capacity: usize,
+    refcount: i32,
+}
 
 impl<'a> Into<&'a CStr> for LuaFileString {
     fn into(self) -> &'a CStr {
-        unsafe { CStr::from_ptr(self.0 as _) }
+        unsafe { CStr::from_ptr(self.0) }
     }
 }
 
@@ -19,6 +30,19 @@ impl<'a> LuaFileString {
     pub fn as_c_str(&self) -> &'a CStr {
         Into::<&CStr>::into(*self)
     }
+
+    fn rep(&self) -> &'a CowStringRep {
+        unsafe { &*(self.0 as *const CowStringRep).sub(1) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rep().length
+    }
+
+    /// Whole string including \0 bytes inside
+    pub fn as_bytes(&self) -> &'a [u8] {
+        unsafe { slice::from_raw_parts(self.0 as *const u8, self.len()) }
+    }
 }
 
 impl Display for LuaFileString {
@@ -32,32 +56,242 @@ impl Display for LuaFileString {
     }
 }
 
+/// `GarrysMod::Lua::LuaFile`, layout is taken from garrysmod_common.
+///
+/// There are no CRC or hash fields. Hash clients check is SHA256 of contents with terminating
+/// \0, it's stored as `client_lua_files` string userdata, not here.
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct LuaFileContent(*const c_void);
-
-impl LuaFileContent {
-    // returns 0 if file is empty. perhaps it's a length?
-    pub fn empty_indicator(&self) -> i32 {
-        /*
-        if (*(int *)((int)luaFile->m_pFileContent + -0xc) == 0) {
-          (**(code **)((int)g_Lua->vmt + 0x1c4))
-                    (g_Lua,"AddCSLuaFile: Empty file \'%s\'\n",luaFile-szFilepath// );
-          goto LAB_009e6d8a;
+#[derive(Debug)]
+pub struct LuaFile {
+    time: i32,
+    name: LuaFileString,
+    source: LuaFileString,
+    contents: LuaFileString,
+    compressed: AutoBuffer,
+    /// Unknown, garrysmod_common initializes it with 1
+    random: c_uint,
+    times_loaded_server: c_uint,
+    times_loaded_client: c_uint,
+}
+
+// offsets of 32-bit srcds are in comments
+const _: () = {
+    use std::mem::{offset_of, size_of};
+
+    const PTR: usize = size_of::<usize>();
+
+    assert!(size_of::<CowStringRep>() == 3 * PTR); // 0x0c
+    assert!(offset_of!(LuaFile, time) == 0);
+    assert!(offset_of!(LuaFile, name) == PTR); // 0x04
+    assert!(offset_of!(LuaFile, source) == 2 * PTR); // 0x08
+    assert!(offset_of!(LuaFile, contents) == 3 * PTR); // 0x0c
+    assert!(offset_of!(LuaFile, compressed) == 4 * PTR); // 0x10
+    assert!(offset_of!(LuaFile, random) == 4 * PTR + size_of::<AutoBuffer>()); // 0x24
+    assert!(offset_of!(LuaFile, times_loaded_server) == offset_of!(LuaFile, random) + 4); // 0x28
+    assert!(offset_of!(LuaFile, times_loaded_client) == offset_of!(LuaFile, random) + 8);
+    // 0x2c
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LuaFileLayoutError {
+    #[error("{0} is null")]
+    NullString(&'static str),
+    #[error("{0} length doesn't match std::string header")]
+    StringLength(&'static str),
+    #[error("compressed buffer positions are out of its allocation")]
+    CompressedBuffer,
+}
+
+impl LuaFile {
+    /// Modification time of the file on disk
+    pub fn time(&self) -> i32 {
+        self.time
+    }
+
+    /// Path relative to GAME search path, e.g. `lua/autorun/client/foo.lua`
+    pub fn name(&self) -> &CStr {
+        self.name.as_c_str()
+    }
+
+    /// Path the file was loaded from, e.g. `addons/foo/lua/autorun/client/foo.lua`
+    pub fn source(&self) -> &CStr {
+        self.source.as_c_str()
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        self.contents.as_bytes()
+    }
+
+    /// LZMA compressed contents with terminating \0, the way they're sent to clients.
+    /// It's filled by `GModDataPack::AddOrUpdateFile` itself, so before the original call
+    /// it's empty or holds the previous version of the file.
+    pub fn compressed(&self) -> Option<&[u8]> {
+        let data = self.compressed.data();
+
+        (!data.is_empty()).then_some(data)
+    }
+
+    /// Buffer clients get file contents from, replacing it changes what vanilla download sends.
+    /// See [`LuaFile::compressed`] for when it's filled.
+    pub fn compressed_mut(&mut self) -> &mut AutoBuffer {
+        &mut self.compressed
+    }
+
+    pub fn times_loaded_server(&self) -> u32 {
+        self.times_loaded_server
+    }
+
+    pub fn times_loaded_client(&self) -> u32 {
+        self.times_loaded_client
+    }
+
+    /// Checks that the struct we got looks like a LuaFile, in case GMod update changed it.
+    /// It's cheap enough to run for every file.
+    pub fn check_layout(&self) -> Result<(), LuaFileLayoutError> {
+        for (field, string) in [
+            ("name", self.name),
+            ("source", self.source),
+            ("contents", self.contents),
+        ] {
+            if string.0.is_null() {
+                return Err(LuaFileLayoutError::NullString(field));
+            }
+
+            let rep = string.rep();
+            let c_len = string.as_c_str().to_bytes().len();
+
+            // paths can't have \0 inside, contents can
+            let length_matches = if field == "contents" {
+                rep.length >= c_len
+            } else {
+                rep.length == c_len
+            };
+
+            if !length_matches || rep.capacity < rep.length {
+                return Err(LuaFileLayoutError::StringLength(field));
+            }
         }
-        */
-        unsafe { *(*(self.0 as *const *const i32).offset(-3)) }
+
+        if self.compressed.written() > self.compressed.size()
+            || self.compressed.pos() > self.compressed.size()
+        {
+            return Err(LuaFileLayoutError::CompressedBuffer);
+        }
+
+        Ok(())
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct LuaFile {
-    pub _unk_1: *const c_void,
-    pub name: LuaFileString,
-    pub kind_of: LuaFileString,
-    pub content: LuaFileString,
-    pub _unk_2: *const AutoBuffer,
-    pub _unk_3: i32,
-    pub _unk_4: i32,
+#[cfg(test)]
+mod tests {
+    use std::{mem::size_of, ptr::null};
+
+    use super::*;
+
+    /// std::string the way libstdc++ allocates it: header, data, \0
+    fn cow_string(data: &[u8]) -> Vec<usize> {
+        let mut words = vec![data.len(), data.len(), 0];
+        let mut bytes = data.to_vec();
+
+        bytes.push(0);
+        bytes.resize(bytes.len().next_multiple_of(size_of::<usize>()), 0);
+
+        for word in bytes.chunks(size_of::<usize>()) {
+            words.push(usize::from_ne_bytes(word.try_into().unwrap()));
+        }
+
+        words
+    }
+
+    fn data_ptr(string: &[usize]) -> usize {
+        string[3..].as_ptr() as usize
+    }
+
+    /// Memory image of engine LuaFile, built word by word instead of through the struct
+    fn lua_file_image(
+        name: &[usize],
+        source: &[usize],
+        contents: &[usize],
+        compressed: &[u8],
+    ) -> Vec<usize> {
+        let mut words = vec![
+            1234, // time
+            data_ptr(name),
+            data_ptr(source),
+            data_ptr(contents),
+            // AutoBuffer: vtable, data, size, pos, written
+            0,
+            compressed.as_ptr() as usize,
+        ];
+
+        let mut ints = vec![compressed.len() as u32, 0, compressed.len() as u32];
+
+        if size_of::<usize>() == 8 {
+            ints.push(0); // AutoBuffer padding
+        }
+
+        ints.extend([1, 3, 5]); // random, times loaded by server and client
+
+        if ints.len() % 2 == 1 && size_of::<usize>() == 8 {
+            ints.push(0);
+        }
+
+        let int_bytes: Vec<u8> = ints.iter().flat_map(|int| int.to_ne_bytes()).collect();
+
+        for word in int_bytes.chunks(size_of::<usize>()) {
+            words.push(usize::from_ne_bytes(word.try_into().unwrap()));
+        }
+
+        words
+    }
+
+    #[test]
+    fn reads_engine_layout() {
+        let name = cow_string(b"lua/autorun/client/a.lua");
+        let source = cow_string(b"addons/a/lua/autorun/client/a.lua");
+        let contents = cow_string(b"print(1)\0print(2)");
+        let compressed = b"lzma";
+        let image = lua_file_image(&name, &source, &contents, compressed);
+
+        assert!(image.len() * size_of::<usize>() >= size_of::<LuaFile>());
+
+        let file = unsafe { &*(image.as_ptr() as *const LuaFile) };
+
+        file.check_layout().unwrap();
+
+        assert_eq!(file.time(), 1234);
+        assert_eq!(file.name(), c"lua/autorun/client/a.lua");
+        assert_eq!(file.source(), c"addons/a/lua/autorun/client/a.lua");
+        assert_eq!(file.contents(), b"print(1)\0print(2)");
+        assert_eq!(file.compressed(), Some(&compressed[..]));
+        assert_eq!(file.times_loaded_server(), 3);
+        assert_eq!(file.times_loaded_client(), 5);
+    }
+
+    #[test]
+    fn rejects_shifted_layout() {
+        let name = cow_string(b"lua/a.lua");
+        let contents = cow_string(b"print(1)");
+
+        // name length doesn't match header
+        let mut bad_name = name.clone();
+        bad_name[0] += 1;
+
+        let image = lua_file_image(&bad_name, &name, &contents, b"");
+        let file = unsafe { &*(image.as_ptr() as *const LuaFile) };
+
+        assert!(matches!(
+            file.check_layout(),
+            Err(LuaFileLayoutError::StringLength("name"))
+        ));
+
+        let mut image = lua_file_image(&name, &name, &contents, b"");
+        image[2] = null::<c_char>() as usize;
+        let file = unsafe { &*(image.as_ptr() as *const LuaFile) };
+
+        assert!(matches!(
+            file.check_layout(),
+            Err(LuaFileLayoutError::NullString("source"))
+        ));
+    }
 }
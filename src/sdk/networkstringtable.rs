@@ -103,3 +103,135 @@ impl<'a> WrappedNetworkStringTable {
         }
     }
 }
+
+/// Network string table for tests, calls go through a Rust vtable instead of the engine
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::{
+        ffi::{c_char, c_int, c_void, CStr, CString},
+        mem::zeroed,
+        ptr::null,
+        slice,
+        sync::Mutex,
+    };
+
+    use super::{NetworkStringTable, NetworkStringTableVTable, WrappedNetworkStringTable};
+
+    #[repr(C)]
+    pub struct FakeNetworkStringTable {
+        // must be first, vtable functions cast table pointer back to this struct
+        table: NetworkStringTable,
+        _vtable: Box<NetworkStringTableVTable>,
+        strings: Mutex<Vec<(CString, Vec<u8>)>>,
+    }
+
+    unsafe fn strings(table: *const NetworkStringTable) -> &'static Mutex<Vec<(CString, Vec<u8>)>> {
+        &(*(table as *const FakeNetworkStringTable)).strings
+    }
+
+    unsafe extern "C" fn num_strings(table: *const NetworkStringTable) -> c_int {
+        strings(table).lock().unwrap().len() as _
+    }
+
+    unsafe extern "C" fn add_string(
+        table: *const NetworkStringTable,
+        _server: bool,
+        value: *const c_char,
+        len: c_int,
+        userdata: *const c_void,
+    ) -> c_int {
+        let value = CStr::from_ptr(value).to_owned();
+        let userdata = if len < 0 {
+            vec![]
+        } else {
+            slice::from_raw_parts(userdata as *const u8, len as _).to_vec()
+        };
+
+        let mut strings = strings(table).lock().unwrap();
+
+        if let Some(index) = strings.iter().position(|(string, _)| *string == value) {
+            return index as _;
+        }
+
+        strings.push((value, userdata));
+
+        (strings.len() - 1) as _
+    }
+
+    unsafe extern "C" fn string(table: *const NetworkStringTable, index: c_int) -> *const c_char {
+        strings(table)
+            .lock()
+            .unwrap()
+            .get(index as usize)
+            .map(|(string, _)| string.as_ptr())
+            .unwrap_or(null())
+    }
+
+    unsafe extern "C" fn set_string_userdata(
+        table: *const NetworkStringTable,
+        index: c_int,
+        len: c_int,
+        userdata: *const c_void,
+    ) {
+        if let Some((_, old)) = strings(table).lock().unwrap().get_mut(index as usize) {
+            *old = slice::from_raw_parts(userdata as *const u8, len as _).to_vec();
+        }
+    }
+
+    impl FakeNetworkStringTable {
+        /// Boxed, so the table pointer given to engine code stays valid
+        pub fn new() -> Box<FakeNetworkStringTable> {
+            let vtable = Box::new(NetworkStringTableVTable {
+                destructor_1: null(),
+                destructor_2: null(),
+                table_name: null(),
+                table_id: null(),
+                num_strings,
+                max_strings: null(),
+                entry_bits: null(),
+                set_tick: null(),
+                changed_since_tick: null(),
+                add_string,
+                string,
+                set_string_userdata,
+                string_userdata: null(),
+                find_string_index: null(),
+                set_string_changed_callback: null(),
+                dump: null(),
+                lock: null(),
+            });
+
+            let mut table: NetworkStringTable = unsafe { zeroed() };
+
+            table.vtable = vtable.as_ref();
+
+            Box::new(FakeNetworkStringTable {
+                table,
+                _vtable: vtable,
+                strings: Mutex::new(vec![]),
+            })
+        }
+
+        pub fn wrapped(&self) -> WrappedNetworkStringTable {
+            WrappedNetworkStringTable(&self.table)
+        }
+
+        pub fn strings(&self) -> Vec<String> {
+            self.strings
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(string, _)| string.to_string_lossy().to_string())
+                .collect()
+        }
+
+        pub fn userdata(&self, value: &str) -> Option<Vec<u8>> {
+            self.strings
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(string, _)| string.to_bytes() == value.as_bytes())
+                .map(|(_, userdata)| userdata.clone())
+        }
+    }
+}
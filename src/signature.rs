@@ -0,0 +1,318 @@
+use std::borrow::Cow;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PatternError {
+    #[error("Pattern is empty")]
+    Empty,
+    #[error("Invalid pattern byte \"{0}\"")]
+    InvalidByte(String),
+    #[error("Pattern starts with wildcard")]
+    LeadingWildcard,
+}
+
+/// How to get target address from signature match
+#[derive(Debug, Clone, Copy)]
+pub enum Resolve {
+    /// Match itself is the target
+    Direct,
+    /// Match contains `call`/`jmp` with rel32 operand at given offset, target is its destination
+    Relative { offset: usize },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureFileError {
+    #[error("Line {0}: expected \"<hook name> = <pattern> [@ <rel32 offset>]\"")]
+    InvalidLine(usize),
+    #[error("Line {0}: invalid rel32 offset \"{1}\"")]
+    InvalidOffset(usize, String),
+    #[error("Line {0}: {1}")]
+    Pattern(usize, PatternError),
+}
+
+/// IDA-style byte signature: `55 89 E5 ?? ?? 8B 45`
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub pattern: Cow<'static, str>,
+    pub resolve: Resolve,
+}
+
+/// Signature of a hook read from signatures file, `hook` is [`crate::hooks::Hook::name`]
+#[derive(Debug, Clone)]
+pub struct NamedSignature {
+    pub hook: String,
+    pub signature: Signature,
+}
+
+#[derive(Debug)]
+pub struct Pattern(Vec<Option<u8>>);
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, PatternError> {
+        let bytes = pattern
+            .split_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Ok(None),
+                _ => u8::from_str_radix(byte, 16)
+                    .map(Some)
+                    .or_else(|_| Err(PatternError::InvalidByte(byte.to_string()))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match bytes.first() {
+            None => Err(PatternError::Empty),
+            Some(None) => Err(PatternError::LeadingWildcard),
+            Some(Some(_)) => Ok(Pattern(bytes)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn matches_at(&self, data: &[u8]) -> bool {
+        self.0
+            .iter()
+            .zip(data)
+            .all(|(pattern_byte, byte)| pattern_byte.is_none_or(|b| b == *byte))
+    }
+
+    /// Offset of first match in data
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        if self.is_empty() || data.len() < self.len() {
+            return None;
+        }
+
+        // parsing rejects leading wildcards
+        let first = self.0[0].unwrap();
+
+        (0..=data.len() - self.len())
+            .find(|&offset| data[offset] == first && self.matches_at(&data[offset..]))
+    }
+}
+
+impl Resolve {
+    /// Resolves target address, `data` must start at the match
+    pub fn apply(&self, address: u64, data: &[u8]) -> Option<u64> {
+        match *self {
+            Resolve::Direct => Some(address),
+            Resolve::Relative { offset } => {
+                let rel = i32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+
+                Some(
+                    address
+                        .wrapping_add((offset + 4) as u64)
+                        .wrapping_add(rel as i64 as u64),
+                )
+            }
+        }
+    }
+}
+
+/// Parses signatures file, so servers can fix hooks broken by GMod update without rebuilding
+/// the module. One signature per line, `#` starts a comment:
+///
+/// ```text
+/// GModDataPack::AddOrUpdateFile = 55 89 E5 57 56 53 ?? ?? 8B 45
+/// CVEngineServer::GMOD_SendToClient = E8 ?? ?? ?? ?? 83 C4 10 @ 1
+/// ```
+///
+/// `@ offset` means the match contains `call`/`jmp` with rel32 operand at `offset`.
+pub fn parse_signature_file(text: &str) -> Result<Vec<NamedSignature>, SignatureFileError> {
+    let mut signatures = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (hook, rest) = line
+            .split_once('=')
+            .ok_or(SignatureFileError::InvalidLine(line_number))?;
+
+        let (pattern, resolve) = match rest.split_once('@') {
+            Some((pattern, offset)) => (
+                pattern,
+                Resolve::Relative {
+                    offset: offset.trim().parse().or_else(|_| {
+                        Err(SignatureFileError::InvalidOffset(
+                            line_number,
+                            offset.trim().to_string(),
+                        ))
+                    })?,
+                },
+            ),
+            None => (rest, Resolve::Direct),
+        };
+
+        let (hook, pattern) = (hook.trim(), pattern.trim());
+
+        if hook.is_empty() {
+            return Err(SignatureFileError::InvalidLine(line_number));
+        }
+
+        Pattern::parse(pattern)
+            .or_else(|err| Err(SignatureFileError::Pattern(line_number, err)))?;
+
+        signatures.push(NamedSignature {
+            hook: hook.to_string(),
+            signature: Signature {
+                pattern: Cow::Owned(pattern.to_string()),
+                resolve,
+            },
+        });
+    }
+
+    Ok(signatures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_patterns() {
+        let pattern = Pattern::parse("55 89 E5 ?? ? 8b").unwrap();
+
+        assert_eq!(pattern.len(), 6);
+        assert!(!pattern.is_empty());
+
+        assert!(matches!(Pattern::parse(""), Err(PatternError::Empty)));
+        assert!(matches!(Pattern::parse("   "), Err(PatternError::Empty)));
+        assert!(matches!(
+            Pattern::parse("?? 55"),
+            Err(PatternError::LeadingWildcard)
+        ));
+        assert!(matches!(
+            Pattern::parse("55 XY"),
+            Err(PatternError::InvalidByte(byte)) if byte == "XY"
+        ));
+        assert!(matches!(
+            Pattern::parse("55 123"),
+            Err(PatternError::InvalidByte(_))
+        ));
+    }
+
+    #[test]
+    fn finds_with_wildcards() {
+        let data = [0x90, 0x55, 0x89, 0x00, 0x55, 0x89, 0xE5, 0x12, 0x34, 0x8B];
+        let pattern = Pattern::parse("55 89 E5 ?? ?? 8B").unwrap();
+
+        assert_eq!(pattern.find(&data), Some(4));
+        assert_eq!(Pattern::parse("55 ?? 00").unwrap().find(&data), Some(1));
+    }
+
+    #[test]
+    fn finds_first_match() {
+        let data = [0xAA, 0xBB, 0xAA, 0xBB];
+
+        assert_eq!(Pattern::parse("AA BB").unwrap().find(&data), Some(0));
+    }
+
+    #[test]
+    fn finds_nothing() {
+        let pattern = Pattern::parse("55 89 E5").unwrap();
+
+        assert_eq!(pattern.find(&[]), None);
+        assert_eq!(pattern.find(&[0x55, 0x89]), None);
+        assert_eq!(pattern.find(&[0x55, 0x89, 0xE4, 0x55, 0x89]), None);
+    }
+
+    #[test]
+    fn finds_at_end_of_buffer() {
+        let pattern = Pattern::parse("55 ?? E5").unwrap();
+
+        assert_eq!(pattern.find(&[0x00, 0x00, 0x55, 0x01, 0xE5]), Some(2));
+        assert_eq!(pattern.find(&[0x55, 0x01, 0xE5]), Some(0));
+        // wildcard can't match past the end
+        assert_eq!(
+            Pattern::parse("E5 ??").unwrap().find(&[0x55, 0x01, 0xE5]),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_direct() {
+        assert_eq!(Resolve::Direct.apply(0x1000, &[]), Some(0x1000));
+    }
+
+    #[test]
+    fn resolves_relative() {
+        // call +0x10 at 0x1000, next instruction is at 0x1005
+        let call = [0xE8, 0x10, 0x00, 0x00, 0x00];
+
+        assert_eq!(
+            Resolve::Relative { offset: 1 }.apply(0x1000, &call),
+            Some(0x1015)
+        );
+
+        // jmp -0x10
+        let jmp = [0xE9, 0xF0, 0xFF, 0xFF, 0xFF];
+
+        assert_eq!(
+            Resolve::Relative { offset: 1 }.apply(0x1000, &jmp),
+            Some(0xFF5)
+        );
+
+        // operand is cut off by end of segment
+        assert_eq!(
+            Resolve::Relative { offset: 1 }.apply(0x1000, &call[..4]),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_signature_file() {
+        let signatures = parse_signature_file(
+            "# fallback signatures\n\
+             \n\
+             GModDataPack::AddOrUpdateFile = 55 89 E5 ?? 8B # prologue\n\
+             CVEngineServer::GMOD_SendToClient (all clients) = E8 ?? ?? ?? ?? 83 @ 1\n",
+        )
+        .unwrap();
+
+        assert_eq!(signatures.len(), 2);
+
+        assert_eq!(signatures[0].hook, "GModDataPack::AddOrUpdateFile");
+        assert_eq!(signatures[0].signature.pattern, "55 89 E5 ?? 8B");
+        assert!(matches!(signatures[0].signature.resolve, Resolve::Direct));
+
+        assert_eq!(
+            signatures[1].hook,
+            "CVEngineServer::GMOD_SendToClient (all clients)"
+        );
+        assert!(matches!(
+            signatures[1].signature.resolve,
+            Resolve::Relative { offset: 1 }
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_signature_file() {
+        assert!(matches!(
+            parse_signature_file("\nno equals sign"),
+            Err(SignatureFileError::InvalidLine(2))
+        ));
+        assert!(matches!(
+            parse_signature_file(" = 55 89"),
+            Err(SignatureFileError::InvalidLine(1))
+        ));
+        assert!(matches!(
+            parse_signature_file("Hook = 55 @ x"),
+            Err(SignatureFileError::InvalidOffset(1, offset)) if offset == "x"
+        ));
+        assert!(matches!(
+            parse_signature_file("Hook = ?? 55"),
+            Err(SignatureFileError::Pattern(
+                1,
+                PatternError::LeadingWildcard
+            ))
+        ));
+    }
+}